@@ -0,0 +1,124 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use rdfs::catalog::{Backend, Catalog};
+use rdfs::compression::Codec;
+use rdfs::master::{Host, MetaStore, Policy, Status};
+use std::collections::HashSet;
+
+const FILES: usize = 250_000;
+const CHUNKS_PER_FILE: i32 = 4;
+const WORKERS: usize = 20;
+
+/* -------------------------------------------------------------------------------------------------
+compares the catalog with the plain `Vec` the master used to keep its metadata in, at a million
+chunks. The `vec` benchmarks do exactly what the handlers used to do, cloning included, so run them
+with `cargo bench --bench catalog` and compare each pair.
+------------------------------------------------------------------------------------------------- */
+fn chunks() -> Vec<MetaStore> {
+    let mut chunks = Vec::with_capacity(FILES * CHUNKS_PER_FILE as usize);
+    for file in 0..FILES {
+        for chunk_id in 1..=CHUNKS_PER_FILE {
+            let n = file * CHUNKS_PER_FILE as usize + chunk_id as usize;
+            chunks.push(MetaStore {
+                file_id: format!("{:032x}", file),
+                file_name: format!("dir-{}/file-{}", file % 100, file),
+                hash: format!("{:064x}", file),
+                chunk_id,
+                chunk_hash: format!("{:064x}", n),
+                size: 512 * 1024,
+                policy: Policy::Replicated,
+                codec: Codec::None,
+                shards: vec![],
+                wrapped_key: String::new(),
+                version: 1,
+                hosts: (0..3)
+                    .map(|x| Host {
+                        ip: format!("10.0.0.{}", (n + x) % WORKERS),
+                        status: Status::Healthy,
+                    })
+                    .collect(),
+            });
+        }
+    }
+    chunks
+}
+
+fn lookups(c: &mut Criterion) {
+    let mut vec = self::chunks();
+    let mut catalog = Catalog::new(vec.clone());
+    let name = format!("dir-42/file-{}", FILES / 2 + 42);
+    let victim = vec[vec.len() / 2].clone();
+    let (ip, stored) = (victim.hosts[0].ip.to_string(), victim.chunk_name());
+
+    let mut group = c.benchmark_group("get");
+    group.sample_size(10);
+    group.bench_function("vec", |b| {
+        b.iter(|| {
+            vec.clone()
+                .into_iter()
+                .filter(|x| x.file_name == name)
+                .collect::<Vec<MetaStore>>()
+        })
+    });
+    group.bench_function("catalog", |b| b.iter(|| catalog.named(black_box(&name))));
+    group.finish();
+
+    let mut group = c.benchmark_group("list");
+    group.sample_size(10);
+    group.bench_function("vec", |b| {
+        b.iter(|| {
+            vec.clone()
+                .into_iter()
+                .map(|x| x.file_name)
+                .collect::<HashSet<_>>()
+                .into_iter()
+                .collect::<Vec<_>>()
+        })
+    });
+    group.bench_function("catalog", |b| b.iter(|| catalog.names()));
+    group.finish();
+
+    let mut group = c.benchmark_group("remove");
+    group.sample_size(10);
+    group.bench_function("vec", |b| {
+        b.iter(|| {
+            let removed: Vec<MetaStore> = vec
+                .iter()
+                .filter(|x| x.file_name == victim.file_name)
+                .cloned()
+                .collect();
+            vec.retain(|x| x.file_name != victim.file_name);
+            vec.extend(removed);
+        })
+    });
+    group.bench_function("catalog", |b| {
+        b.iter(|| {
+            for file_id in catalog.file_ids(&victim.file_name) {
+                for chunk in catalog.remove_file(&file_id) {
+                    catalog.insert(chunk);
+                }
+            }
+        })
+    });
+    group.finish();
+
+    let mut group = c.benchmark_group("report-chunk");
+    group.sample_size(10);
+    group.bench_function("vec", |b| {
+        b.iter(|| {
+            vec.iter()
+                .filter(|x| {
+                    x.placements()
+                        .iter()
+                        .any(|(name, host)| *name == stored && host.ip == ip)
+                })
+                .count()
+        })
+    });
+    group.bench_function("catalog", |b| {
+        b.iter(|| catalog.placed(black_box(&ip), black_box(&stored)).len())
+    });
+    group.finish();
+}
+
+criterion_group!(benches, lookups);
+criterion_main!(benches);
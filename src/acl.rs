@@ -0,0 +1,288 @@
+use axum::{
+    http::StatusCode,
+    response::{IntoResponse, Json, Response},
+    Extension,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::sync::Mutex;
+use tracing::{info, warn};
+
+use crate::auth::{Identity, Scope};
+use crate::raft;
+use crate::raft::Op;
+
+/// where the master keeps the access control lists, one json line per path
+const ACL_STORE: &str = "acls";
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, PartialOrd)]
+#[serde(rename_all = "lowercase")]
+pub enum Permission {
+    Read,
+    Write,
+    Admin,
+}
+
+/// a permission given to either `user:<token name>` or `group:<group name>`
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Grant {
+    pub principal: String,
+    pub permission: Permission,
+}
+
+/// the owner and grants of a file or directory, directories are simply the prefixes of file names
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Acl {
+    pub path: String,
+    pub owner: String,
+    #[serde(default)]
+    pub grants: Vec<Grant>,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct ChmodRequest {
+    pub path: String,
+    pub principal: String,
+    /// no permission removes the grant of the principal
+    pub permission: Option<Permission>,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct ChownRequest {
+    pub path: String,
+    pub owner: String,
+}
+
+lazy_static! {
+    static ref ACLS: Mutex<HashMap<String, Acl>> = Mutex::new(HashMap::new());
+}
+
+/// parses the permission name given on the command line, "none" removes a grant
+pub fn parse_permission(permission: &str) -> Option<Option<Permission>> {
+    match permission.to_lowercase().as_ref() {
+        "none" => Some(None),
+        "read" => Some(Some(Permission::Read)),
+        "write" => Some(Some(Permission::Write)),
+        "admin" => Some(Some(Permission::Admin)),
+        _ => None,
+    }
+}
+
+/// the path itself followed by all of its parent directories, the root being the empty path
+pub fn lineage(path: &str) -> Vec<String> {
+    let mut path = path.trim_matches('/').to_string();
+    let mut lineage = vec![path.to_string()];
+
+    while !path.is_empty() {
+        path = path
+            .rsplit_once('/')
+            .map(|x| x.0)
+            .unwrap_or_default()
+            .to_string();
+        lineage.push(path.to_string());
+    }
+    lineage
+}
+
+/* -------------------------------------------------------------------------------------------------
+permissions are inherited: the owner of a path and everyone granted a permission on it have that
+permission on everything below it as well. Paths without an ACL anywhere in their lineage are left
+open (which covers everything uploaded before ACLs existed), tokens with the admin scope bypass the
+checks altogether.
+------------------------------------------------------------------------------------------------- */
+pub fn permitted(identity: &Identity, path: &str, needed: Permission) -> bool {
+    identity.allows(Scope::Admin) || self::granted(identity, path, needed).unwrap_or(true)
+}
+
+/// changing an ACL always needs an explicit grant, otherwise anyone could claim an open directory
+/// (or the root) and with it everything below
+fn governs(identity: &Identity, path: &str) -> bool {
+    identity.allows(Scope::Admin)
+        || self::granted(identity, path, Permission::Admin).unwrap_or(false)
+}
+
+/// none when no ACL applies to the path at all
+fn granted(identity: &Identity, path: &str, needed: Permission) -> Option<bool> {
+    let acls = ACLS.lock().ok()?;
+    let lineage: Vec<&Acl> = self::lineage(path)
+        .iter()
+        .filter_map(|x| acls.get(x))
+        .collect();
+
+    if lineage.is_empty() {
+        return None;
+    }
+    Some(lineage.iter().any(|acl| {
+        acl.owner == identity.name
+            || acl
+                .grants
+                .iter()
+                .any(|x| x.permission >= needed && self::matches(&x.principal, identity))
+    }))
+}
+
+/// the owner of the path, inherited from the closest parent directory when it has none itself
+pub fn owner(path: &str) -> Option<String> {
+    let acls = ACLS.lock().ok()?;
+    self::lineage(path)
+        .iter()
+        .filter_map(|x| acls.get(x))
+        .map(|x| x.owner.to_string())
+        .find(|x| !x.is_empty())
+}
+
+fn matches(principal: &str, identity: &Identity) -> bool {
+    match principal.split_once(':') {
+        Some(("user", name)) => name == identity.name,
+        Some(("group", name)) => identity.groups.iter().any(|x| x == name),
+        _ => false,
+    }
+}
+
+/// the uploader of a new file becomes its owner
+pub fn claim(path: &str, owner: &str) {
+    if let Ok(mut acls) = ACLS.lock() {
+        let path = path.trim_matches('/').to_string();
+        if !acls.contains_key(&path) {
+            acls.insert(
+                path.to_string(),
+                Acl {
+                    path,
+                    owner: owner.to_string(),
+                    grants: vec![],
+                },
+            );
+            self::save_acls(&acls);
+        }
+    }
+}
+
+/// drops the ACL of a removed file, the ACLs of directories stay
+pub fn forget(path: &str) {
+    if let Ok(mut acls) = ACLS.lock() {
+        if acls.remove(path.trim_matches('/')).is_some() {
+            self::save_acls(&acls);
+        }
+    }
+}
+
+pub fn load_acls() {
+    let loaded: Vec<Acl> = fs::read_to_string(ACL_STORE)
+        .unwrap_or_default()
+        .lines()
+        .filter_map(|x| serde_json::from_str(x).ok())
+        .collect();
+    info!("loaded {} access control list(s)", loaded.len());
+
+    if let Ok(mut acls) = ACLS.lock() {
+        *acls = loaded
+            .into_iter()
+            .map(|x| (x.path.to_string(), x))
+            .collect();
+    }
+}
+
+fn save_acls(acls: &HashMap<String, Acl>) {
+    self::write_acls(acls);
+    raft::record(Op::Acls(acls.values().cloned().collect()));
+}
+
+fn write_acls(acls: &HashMap<String, Acl>) {
+    let lines: Vec<String> = acls
+        .values()
+        .filter_map(|x| serde_json::to_string(x).ok())
+        .collect();
+
+    if let Err(e) = fs::write(ACL_STORE, lines.join("\n") + "\n") {
+        warn!("unable to save the access control lists: {}", e);
+    }
+}
+
+pub fn acls() -> Vec<Acl> {
+    ACLS.lock()
+        .map(|x| x.values().cloned().collect())
+        .unwrap_or_default()
+}
+
+/// takes over the access control lists replicated from the leader
+pub fn replace_acls(replaced: Vec<Acl>) {
+    if let Ok(mut acls) = ACLS.lock() {
+        *acls = replaced
+            .into_iter()
+            .map(|x| (x.path.to_string(), x))
+            .collect();
+        self::write_acls(&acls);
+    }
+}
+
+pub async fn chmod(
+    Extension(identity): Extension<Identity>,
+    Json(payload): Json<ChmodRequest>,
+) -> Response {
+    let valid = matches!(
+        payload.principal.split_once(':'),
+        Some(("user" | "group", name)) if !name.is_empty()
+    );
+    if !valid {
+        return StatusCode::BAD_REQUEST.into_response();
+    }
+    if !self::governs(&identity, &payload.path) {
+        return StatusCode::FORBIDDEN.into_response();
+    }
+
+    let Ok(mut acls) = ACLS.lock() else {
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    };
+    let path = payload.path.trim_matches('/').to_string();
+    let acl = acls.entry(path.to_string()).or_insert(Acl {
+        path,
+        owner: identity.name.to_string(),
+        grants: vec![],
+    });
+
+    acl.grants.retain(|x| x.principal != payload.principal);
+    if let Some(permission) = payload.permission {
+        acl.grants.push(Grant {
+            principal: payload.principal.to_string(),
+            permission,
+        });
+    }
+    let acl = acl.clone();
+    self::save_acls(&acls);
+
+    info!(
+        "[{}] set {:?} for [{}] on [{}]",
+        identity.name, payload.permission, payload.principal, acl.path
+    );
+    Json(acl).into_response()
+}
+
+pub async fn chown(
+    Extension(identity): Extension<Identity>,
+    Json(payload): Json<ChownRequest>,
+) -> Response {
+    if payload.owner.is_empty() {
+        return StatusCode::BAD_REQUEST.into_response();
+    }
+    if !self::governs(&identity, &payload.path) {
+        return StatusCode::FORBIDDEN.into_response();
+    }
+
+    let Ok(mut acls) = ACLS.lock() else {
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    };
+    let path = payload.path.trim_matches('/').to_string();
+    let acl = acls.entry(path.to_string()).or_insert(Acl {
+        path,
+        owner: String::new(),
+        grants: vec![],
+    });
+
+    acl.owner = payload.owner.to_string();
+    let acl = acl.clone();
+    self::save_acls(&acls);
+
+    info!("[{}] gave [{}] to [{}]", identity.name, acl.path, acl.owner);
+    Json(acl).into_response()
+}
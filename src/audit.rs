@@ -0,0 +1,188 @@
+use axum::{
+    body::Body,
+    extract::{ConnectInfo, Request},
+    http::StatusCode,
+    middleware::Next,
+    response::{IntoResponse, Json, Response},
+};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use tracing::{info, warn};
+
+use crate::auth::Identity;
+
+/// where the master appends the audit events, one json line per event
+const AUDIT_LOG: &str = "audit";
+/// once the log grows past this size it is rotated to `audit.1`, older logs move up by one
+const AUDIT_ROTATE_SIZE: u64 = 16 * 1024 * 1024;
+/// how many rotated logs are kept, the oldest one is dropped when rotating
+const AUDIT_KEEP: usize = 5;
+/// the largest body the target of an operation is read from, big uploads list a lot of hashes
+const MAX_AUDITED_BODY: usize = 16 * 1024 * 1024;
+
+/// a single mutating call made to the master
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Event {
+    /// unix timestamp in seconds
+    pub timestamp: i64,
+    /// name of the token the call was made with
+    pub identity: String,
+    pub source: String,
+    pub operation: String,
+    /// the file, directory, token or quota subject the call was about
+    pub target: String,
+    /// the status code the master answered with, failed attempts are recorded as well
+    pub status: u16,
+}
+
+#[derive(Deserialize, Serialize, Default)]
+pub struct AuditQuery {
+    pub user: Option<String>,
+    /// matches the path itself and everything below it
+    pub path: Option<String>,
+    pub since: Option<i64>,
+    pub until: Option<i64>,
+}
+
+lazy_static! {
+    /// appending, rotating and reading the log must not interleave
+    static ref AUDIT: Mutex<()> = Mutex::new(());
+}
+
+/// the routes that change the metadata held by the master
+fn audited(path: &str) -> bool {
+    matches!(
+        path,
+        "/upload"
+            | "/remove"
+            | "/chmod"
+            | "/chown"
+            | "/create-token"
+            | "/revoke-token"
+            | "/set-quota"
+            | "/create-snapshot"
+            | "/restore-snapshot"
+            | "/delete-snapshot"
+    )
+}
+
+/* -------------------------------------------------------------------------------------------------
+the middleware runs after `auth::authorise` so the identity is known, calls that fail to authorise
+never reach the handlers and only show up in the tracing logs. The target is taken from the request
+body (every mutating route names its file, path or token there), so the body is read up front and
+handed on to the route.
+------------------------------------------------------------------------------------------------- */
+pub async fn record(request: Request, next: Next) -> Result<Response, StatusCode> {
+    let operation = request.uri().path().to_string();
+    if !self::audited(&operation) {
+        return Ok(next.run(request).await);
+    }
+
+    let identity = request
+        .extensions()
+        .get::<Identity>()
+        .map(|x| x.name.to_string())
+        .unwrap_or_default();
+    let source = request
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|x| x.0.ip().to_string())
+        .unwrap_or_default();
+
+    let (parts, body) = request.into_parts();
+    let body = axum::body::to_bytes(body, MAX_AUDITED_BODY)
+        .await
+        .map_err(|_| StatusCode::PAYLOAD_TOO_LARGE)?;
+    let target = serde_json::from_slice::<serde_json::Value>(&body)
+        .ok()
+        .and_then(|x| {
+            ["name", "path", "subject"]
+                .iter()
+                .find_map(|y| x.get(y).and_then(|z| z.as_str()).map(|z| z.to_string()))
+        })
+        .unwrap_or_default();
+
+    let response = next.run(Request::from_parts(parts, Body::from(body))).await;
+    self::append(&Event {
+        timestamp: chrono::Utc::now().timestamp(),
+        identity,
+        source,
+        operation: operation.trim_start_matches('/').to_string(),
+        target,
+        status: response.status().as_u16(),
+    });
+    Ok(response)
+}
+
+fn append(event: &Event) {
+    let Ok(_guard) = AUDIT.lock() else {
+        return;
+    };
+
+    if fs::metadata(AUDIT_LOG).is_ok_and(|x| x.len() >= AUDIT_ROTATE_SIZE) {
+        self::rotate();
+    }
+
+    let written = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(AUDIT_LOG)
+        .and_then(|mut x| writeln!(x, "{}", serde_json::json!(event)));
+    if let Err(e) = written {
+        warn!("unable to write to the audit log: {}", e);
+    }
+}
+
+fn rotate() {
+    for i in (1..AUDIT_KEEP).rev() {
+        let _ = fs::rename(
+            format!("{}.{}", AUDIT_LOG, i),
+            format!("{}.{}", AUDIT_LOG, i + 1),
+        );
+    }
+    match fs::rename(AUDIT_LOG, format!("{}.1", AUDIT_LOG)) {
+        Ok(_) => info!("rotated the audit log"),
+        Err(e) => warn!("unable to rotate the audit log: {}", e),
+    }
+}
+
+fn matches(event: &Event, query: &AuditQuery) -> bool {
+    let below = |path: &str| {
+        let path = path.trim_matches('/');
+        let target = event.target.trim_matches('/');
+        path.is_empty() || target == path || target.starts_with(&format!("{}/", path))
+    };
+
+    query.user.as_ref().is_none_or(|x| *x == event.identity)
+        && query.path.as_ref().is_none_or(|x| below(x))
+        && query.since.is_none_or(|x| event.timestamp >= x)
+        && query.until.is_none_or(|x| event.timestamp <= x)
+}
+
+/// returns the matching events from the rotated logs and the current one, oldest first
+pub async fn query(Json(payload): Json<AuditQuery>) -> Response {
+    let Ok(_guard) = AUDIT.lock() else {
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    };
+
+    let logs = (1..=AUDIT_KEEP)
+        .rev()
+        .map(|i| format!("{}.{}", AUDIT_LOG, i))
+        .chain([AUDIT_LOG.to_string()]);
+    let events: Vec<Event> = logs
+        .flat_map(|x| {
+            fs::read_to_string(x)
+                .unwrap_or_default()
+                .lines()
+                .filter_map(|y| serde_json::from_str::<Event>(y).ok())
+                .collect::<Vec<_>>()
+        })
+        .filter(|x| self::matches(x, &payload))
+        .collect();
+
+    Json(events).into_response()
+}
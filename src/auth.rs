@@ -1,24 +1,819 @@
 use axum::{
-    extract::Request,
-    http::{HeaderMap, StatusCode},
+    body::Body,
+    extract::{Request, State},
+    http::{StatusCode, Uri},
     middleware::Next,
-    response::Response,
+    response::{IntoResponse, Json, Response},
 };
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::fs;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
 
-use crate::config;
+use crate::checksum;
+use crate::config::Config;
+use crate::master::MetaStore;
+use crate::raft;
+use crate::raft::Op;
+use crate::tls;
+
+/// where the master keeps the named tokens, one json line per token
+const TOKEN_STORE: &str = "tokens";
+/// how long a worker trusts the answer of the master about a token
+const TOKEN_CACHE_IN_SECONDS: u64 = 60;
+/// tokens are referred to by a short id derived from them, e.g when signing requests
+const KEY_ID_SIZE: usize = 16;
+/// share links can't be valid for longer than a week
+pub const MAX_SHARE_IN_SECONDS: i64 = 7 * 24 * 60 * 60;
+/// how long a client has to store the chunks of an upload plan on the workers
+const STORE_CAPABILITY_IN_SECONDS: i64 = 15 * 60;
+/// deletes are sent by the master right away
+const DELETE_CAPABILITY_IN_SECONDS: i64 = 60;
+/// how many requests are made to the masters before giving up on finding the leader
+const MASTER_ATTEMPTS: usize = 10;
+/// the largest body a signed request may have, a base64 encoded chunk is roughly 700 KiB
+const MAX_SIGNED_BODY: usize = 16 * 1024 * 1024;
+
+const TOKEN_HEADER: &str = "x-rdfs-token";
+const KEY_HEADER: &str = "x-rdfs-key";
+const TIMESTAMP_HEADER: &str = "x-rdfs-timestamp";
+const SIGNATURE_HEADER: &str = "x-rdfs-signature";
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Scope {
+    Read,
+    Write,
+    Admin,
+    Worker,
+}
+
+/// what a capability allows a worker to do with a chunk
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Operation {
+    Store,
+    Delete,
+}
+
+/// the master's permission to store or delete a single chunk on the workers
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Capability {
+    pub chunk: String,
+    pub operation: Operation,
+    pub expires: i64,
+    pub signature: String,
+    /// the files referencing the chunk, kept next to it so the master can be recovered from the
+    /// workers, signed along with the rest
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub files: Vec<MetaStore>,
+}
+
+/// parses the scope name given on the command line
+pub fn parse_scope(scope: &str) -> Option<Scope> {
+    match scope.to_lowercase().as_ref() {
+        "read" => Some(Scope::Read),
+        "write" => Some(Scope::Write),
+        "admin" => Some(Scope::Admin),
+        "worker" => Some(Scope::Worker),
+        _ => None,
+    }
+}
+
+/// who is making a request, attached to every authorised request
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Identity {
+    pub name: String,
+    pub scopes: Vec<Scope>,
+    #[serde(default)]
+    pub groups: Vec<String>,
+}
+
+impl Identity {
+    /// the cluster token (RDFS_TOKEN) is used by the nodes themselves and may do anything
+    fn cluster() -> Identity {
+        Identity {
+            name: String::from("cluster"),
+            scopes: vec![Scope::Read, Scope::Write, Scope::Admin, Scope::Worker],
+            groups: vec![],
+        }
+    }
+
+    /// admin implies read and write, only the worker scope has to be granted explicitly
+    pub fn allows(&self, scope: Scope) -> bool {
+        self.scopes.contains(&scope)
+            || (scope != Scope::Worker && self.scopes.contains(&Scope::Admin))
+    }
+}
+
+/// neither the token nor its hash is kept, only what the master derives it from (see `derive`)
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct TokenEntry {
+    name: String,
+    /// the key id of the token
+    #[serde(default)]
+    key: String,
+    #[serde(default)]
+    nonce: String,
+    scopes: Vec<Scope>,
+    #[serde(default)]
+    groups: Vec<String>,
+}
+
+impl TokenEntry {
+    fn identity(&self) -> Identity {
+        Identity {
+            name: self.name.to_string(),
+            scopes: self.scopes.clone(),
+            groups: self.groups.clone(),
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct TokenRequest {
+    pub name: String,
+    #[serde(default)]
+    pub scopes: Vec<Scope>,
+    #[serde(default)]
+    pub groups: Vec<String>,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct NewToken {
+    pub name: String,
+    pub token: String,
+    pub scopes: Vec<Scope>,
+}
+
+/// what a request shows of its token, workers hand it to the master when they don't know the token
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(rename_all = "lowercase")]
+pub enum Proof {
+    /// the token itself, as sent in the `x-rdfs-token` header
+    Token(String),
+    /// the canonical string of a signed request (or share link) along with its signature
+    Signed {
+        key: String,
+        canonical: String,
+        signature: String,
+    },
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct Introspect {
+    pub proof: Proof,
+}
+
+/// the master's answer about a proof, which never includes the secret behind it
+#[derive(Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Verdict {
+    Valid(Identity),
+    /// the token is known but the proof doesn't match it
+    Invalid,
+    Unknown,
+}
+
+impl Verdict {
+    fn identity(self) -> Option<Identity> {
+        match self {
+            Verdict::Valid(identity) => Some(identity),
+            _ => None,
+        }
+    }
+}
+
+impl Proof {
+    fn key(&self) -> String {
+        match self {
+            Proof::Token(token) => self::key_id(token),
+            Proof::Signed { key, .. } => key.to_string(),
+        }
+    }
+
+    /// checks the proof against the token it claims to be made with
+    fn verify(&self, token: &str, identity: Identity) -> Verdict {
+        let valid = match self {
+            Proof::Token(given) => self::constant_time_eq(given.as_bytes(), token.as_bytes()),
+            Proof::Signed {
+                canonical,
+                signature,
+                ..
+            } => {
+                let expected = self::sign(&self::signing_secret(token), canonical);
+                self::constant_time_eq(expected.as_bytes(), signature.as_bytes())
+            }
+        };
+        match valid {
+            true => Verdict::Valid(identity),
+            false => Verdict::Invalid,
+        }
+    }
+}
+
+lazy_static! {
+    /// the token store only exists on the master, workers ask the master instead
+    static ref TOKENS: Mutex<Option<Vec<TokenEntry>>> = Mutex::new(None);
+    /// the identities of tokens the master vouched for, by the hash of the token
+    static ref TOKENCACHE: Mutex<HashMap<String, (Identity, Instant)>> = Mutex::new(HashMap::new());
+    /// signatures seen within the clock skew window, a signature is only accepted once
+    static ref SIGNATURES: Mutex<HashMap<String, i64>> = Mutex::new(HashMap::new());
+    /// the master that took the last request, tried first when there are several
+    static ref LEADER: Mutex<Option<String>> = Mutex::new(None);
+}
 
 pub async fn authorise(
-    headers: HeaderMap,
+    State(config): State<Config>,
     request: Request,
     next: Next,
 ) -> Result<Response, StatusCode> {
-    if let Some(config) = config::get() {
-        if let Some(token) = headers.get("x-rdfs-token") {
-            if *token == *config.token {
-                let response = next.run(request).await;
+    let (identity, mut request) = match request.headers().get(SIGNATURE_HEADER) {
+        Some(_) => self::verify_signature(&config, request).await?,
+        // once requests are signed a plain token is no longer good enough
+        None if config.signed_requests => return Err(StatusCode::UNAUTHORIZED),
+        None => {
+            let token = request
+                .headers()
+                .get(TOKEN_HEADER)
+                .and_then(|x| x.to_str().ok())
+                .ok_or(StatusCode::UNAUTHORIZED)?;
+            let identity = self::authenticate(&config, Proof::Token(token.to_string()))
+                .await
+                .ok_or(StatusCode::UNAUTHORIZED)?;
+            (identity, request)
+        }
+    };
+
+    let scope = self::required_scope(request.uri().path());
+    if !identity.allows(scope) {
+        warn!(
+            "token [{}] is missing the {:?} scope for {}",
+            identity.name,
+            scope,
+            request.uri().path()
+        );
+        return Err(StatusCode::FORBIDDEN);
+    }
+    if self::internal(request.uri().path()) && !self::cluster_node(&config, &identity, &request) {
+        warn!(
+            "token [{}] is not one of the cluster's own for {}",
+            identity.name,
+            request.uri().path()
+        );
+        return Err(StatusCode::FORBIDDEN);
+    }
+
+    request.extensions_mut().insert(identity);
+    Ok(next.run(request).await)
+}
+
+/// the scope needed for every route of the master and worker nodes, unknown routes need admin
+fn required_scope(path: &str) -> Scope {
+    match path {
+        "/list" | "/get" | "/get-chunk" | "/quota" | "/list-snapshots" => Scope::Read,
+        "/upload" | "/remove" | "/store-chunk" | "/chmod" | "/chown" | "/create-snapshot"
+        | "/restore-snapshot" | "/delete-snapshot" => Scope::Write,
+        "/heartbeat" | "/report-chunk" | "/introspect" | "/send-chunk" | "/delete-chunk"
+        | "/tag-chunk" | "/list-chunks" | "/raft/vote" | "/raft/append" | "/raft/install"
+        | "/oplog" => Scope::Worker,
+        _ => Scope::Admin,
+    }
+}
+
+/// the routes only the nodes call on each other, a token with the worker scope isn't enough
+fn internal(path: &str) -> bool {
+    matches!(path, "/introspect")
+}
+
+/// the cluster token, or a node presenting a client certificate issued to one of the masters
+fn cluster_node(config: &Config, identity: &Identity, request: &Request) -> bool {
+    identity.name == "cluster"
+        || tls::peer(request.extensions()).is_some_and(|name| {
+            config.endpoints.iter().any(|x| {
+                url::Url::parse(x)
+                    .ok()
+                    .is_some_and(|y| y.host_str() == Some(name.as_str()))
+            })
+        })
+}
+
+/* -------------------------------------------------------------------------------------------------
+signed requests never send the token itself, instead the client sends the id of its key, a unix
+timestamp and a HMAC-SHA256 over the method, path, body hash and that timestamp keyed with a secret
+derived from the token. Requests outside the clock skew window are rejected and within the window a
+signature is only accepted once, so captured requests can't be replayed.
+------------------------------------------------------------------------------------------------- */
+async fn verify_signature(
+    config: &Config,
+    request: Request,
+) -> Result<(Identity, Request), StatusCode> {
+    let (parts, body) = request.into_parts();
+    let header = |name: &str| {
+        parts
+            .headers
+            .get(name)
+            .and_then(|x| x.to_str().ok())
+            .map(|x| x.to_string())
+            .ok_or(StatusCode::UNAUTHORIZED)
+    };
+    let (key, signature) = (header(KEY_HEADER)?, header(SIGNATURE_HEADER)?);
+    let timestamp: i64 = header(TIMESTAMP_HEADER)?
+        .parse()
+        .map_err(|_| StatusCode::UNAUTHORIZED)?;
+
+    let now = chrono::Utc::now().timestamp();
+    if now.abs_diff(timestamp) > config.clock_skew {
+        warn!("rejected signed request outside of the clock skew window");
+        return Err(StatusCode::UNAUTHORIZED);
+    }
+
+    let body = axum::body::to_bytes(body, MAX_SIGNED_BODY)
+        .await
+        .map_err(|_| StatusCode::PAYLOAD_TOO_LARGE)?;
+
+    let path = parts
+        .uri
+        .path_and_query()
+        .map(|x| x.as_str())
+        .unwrap_or("/");
+    let proof = Proof::Signed {
+        key,
+        canonical: self::canonical(parts.method.as_str(), path, &body, timestamp),
+        signature: signature.to_string(),
+    };
+    let identity = self::authenticate(config, proof)
+        .await
+        .ok_or(StatusCode::UNAUTHORIZED)?;
+
+    if let Ok(mut seen) = SIGNATURES.lock() {
+        seen.retain(|_, x| now.abs_diff(*x) <= config.clock_skew);
+        if seen.insert(signature, timestamp).is_some() {
+            warn!("rejected replayed request from [{}]", identity.name);
+            return Err(StatusCode::UNAUTHORIZED);
+        }
+    }
+
+    Ok((identity, Request::from_parts(parts, Body::from(body))))
+}
+
+fn canonical(method: &str, path: &str, body: &[u8], timestamp: i64) -> String {
+    format!(
+        "{}\n{}\n{}\n{}",
+        method,
+        path,
+        checksum::hash(body),
+        timestamp
+    )
+}
+
+/// hex HMAC-SHA256, what every key and signature is made of
+fn sign(secret: &str, data: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("any key size works");
+    mac.update(data.as_bytes());
+    format!("{:x}", mac.finalize().into_bytes())
+}
+
+/// the id a token is looked up by, sent along with signed requests and share links
+fn key_id(token: &str) -> String {
+    self::sign(token, "rdfs-key")[..KEY_ID_SIZE].to_string()
+}
+
+/// what requests and share links are signed with, only the token's owner and the master know it
+fn signing_secret(token: &str) -> String {
+    self::sign(token, "rdfs-signing")
+}
+
+/* -------------------------------------------------------------------------------------------------
+named tokens are never stored, not even as a hash: the master keeps a random nonce for every token
+and the token is the HMAC of that nonce under a key derived from the cluster token, which only the
+nodes know. The token store is useless to whoever gets hold of it, and changing the cluster token
+revokes every named token along with it.
+------------------------------------------------------------------------------------------------- */
+fn derive(config: &Config, nonce: &str) -> String {
+    self::sign(&self::sign(&config.token, "rdfs-token-key"), nonce)
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |x, (a, b)| x | (a ^ b)) == 0
+}
+
+/* -------------------------------------------------------------------------------------------------
+share links are signed the same way as requests, the file name takes the place of the body and the
+expiry the place of the timestamp. The link stays tied to the token that signed it, so revoking the
+token (or taking away its read scope) also revokes every link it has shared.
+------------------------------------------------------------------------------------------------- */
+/// signs a download link for a file, returns the key id and the signature
+pub fn sign_share(token: &str, file: &str, expires: i64) -> (String, String) {
+    let canonical = self::canonical("GET", "/share", file.as_bytes(), expires);
+    let signature = self::sign(&self::signing_secret(token), &canonical);
+    (self::key_id(token), signature)
+}
+
+/// returns who shared the file if the link is valid and has not expired yet
+pub async fn verify_share(
+    config: &Config,
+    file: &str,
+    expires: i64,
+    key: &str,
+    signature: &str,
+) -> Option<Identity> {
+    let now = chrono::Utc::now().timestamp();
+    if expires < now || expires - now > MAX_SHARE_IN_SECONDS {
+        return None;
+    }
+
+    let proof = Proof::Signed {
+        key: key.to_string(),
+        canonical: self::canonical("GET", "/share", file.as_bytes(), expires),
+        signature: signature.to_string(),
+    };
+    self::authenticate(config, proof)
+        .await
+        .filter(|x| x.allows(Scope::Read))
+}
+
+/* -------------------------------------------------------------------------------------------------
+a token with the write or worker scope would otherwise be enough to overwrite or delete any chunk
+on the workers, so the master hands out a capability for every chunk it plans to store or delete.
+It is signed (like a request to the worker route) with the hash of the cluster token, which only
+the nodes know, so the workers can check it without asking the master. Capabilities to store a
+chunk also carry the metadata of the files referencing it, which is signed too since the workers
+keep it around for rebuilding the master's metadata (see `recovery`).
+------------------------------------------------------------------------------------------------- */
+pub fn grant(token: &str, chunk: &str, operation: Operation, files: Vec<MetaStore>) -> Capability {
+    let lifetime = match operation {
+        Operation::Store => STORE_CAPABILITY_IN_SECONDS,
+        Operation::Delete => DELETE_CAPABILITY_IN_SECONDS,
+    };
+    let expires = chrono::Utc::now().timestamp() + lifetime;
+
+    Capability {
+        chunk: chunk.to_string(),
+        operation,
+        expires,
+        signature: self::capability_signature(token, chunk, operation, expires, &files),
+        files,
+    }
+}
+
+/// checks that the capability was granted by the master for this chunk and operation
+pub fn permits(
+    token: &str,
+    capability: Option<&Capability>,
+    chunk: &str,
+    operation: Operation,
+) -> bool {
+    let Some(capability) = capability else {
+        return false;
+    };
+    let expected = self::capability_signature(
+        token,
+        chunk,
+        operation,
+        capability.expires,
+        &capability.files,
+    );
+
+    capability.chunk == chunk
+        && capability.operation == operation
+        && capability.expires >= chrono::Utc::now().timestamp()
+        && self::constant_time_eq(expected.as_bytes(), capability.signature.as_bytes())
+}
+
+fn capability_signature(
+    token: &str,
+    chunk: &str,
+    operation: Operation,
+    expires: i64,
+    files: &[MetaStore],
+) -> String {
+    let path = match operation {
+        Operation::Store => "/store-chunk",
+        Operation::Delete => "/delete-chunk",
+    };
+    let mut body = chunk.as_bytes().to_vec();
+    if !files.is_empty() {
+        body.extend(serde_json::to_vec(files).unwrap_or_default());
+    }
+    let secret = checksum::hash(token.as_bytes());
+    self::sign(&secret, &self::canonical("POST", path, &body, expires))
+}
+
+/// sends an authorised json request with the configured token, signed when the cluster is set up
+/// to use signed requests
+pub fn send<T: Serialize>(config: &Config, url: &str, data: T) -> Result<ureq::Response, String> {
+    self::dispatch(config, url, data, None).map_err(|e| self::describe(*e))
+}
+
+/// like `send`, but gives up once the node takes longer than the timeout to answer
+pub fn send_within<T: Serialize>(
+    config: &Config,
+    url: &str,
+    data: T,
+    timeout: Duration,
+) -> Result<ureq::Response, String> {
+    self::dispatch(config, url, data, Some(timeout)).map_err(|e| self::describe(*e))
+}
+
+fn dispatch<T: Serialize>(
+    config: &Config,
+    url: &str,
+    data: T,
+    timeout: Option<Duration>,
+) -> Result<ureq::Response, Box<ureq::Error>> {
+    let mut request = tls::agent().post(url);
+    if let Some(timeout) = timeout {
+        request = request.timeout(timeout);
+    }
+    if !config.signed_requests {
+        return Ok(request.set(TOKEN_HEADER, &config.token).send_json(data)?);
+    }
+
+    let body = serde_json::to_vec(&data).map_err(|e| ureq::Error::from(std::io::Error::from(e)))?;
+    let path = url
+        .parse::<Uri>()
+        .ok()
+        .and_then(|x| x.path_and_query().map(|x| x.to_string()))
+        .unwrap_or(String::from("/"));
+    let timestamp = chrono::Utc::now().timestamp();
+    let signature = self::sign(
+        &self::signing_secret(&config.token),
+        &self::canonical("POST", &path, &body, timestamp),
+    );
+
+    request
+        .set(KEY_HEADER, &self::key_id(&config.token))
+        .set(TIMESTAMP_HEADER, &timestamp.to_string())
+        .set(SIGNATURE_HEADER, &signature)
+        .set("Content-Type", "application/json")
+        .send_bytes(&body)
+        .map_err(Box::new)
+}
+
+/* -------------------------------------------------------------------------------------------------
+with several masters only the leader takes writes, the others redirect to it or answer with a 503
+(and a Retry-After) while there is no leader. Requests go to the master that answered last, moving
+on to the next endpoint whenever one can't be reached.
+------------------------------------------------------------------------------------------------- */
+pub fn send_master<T: Serialize>(
+    config: &Config,
+    route: &str,
+    data: T,
+) -> Result<ureq::Response, String> {
+    let data = serde_json::to_value(data).map_err(|e| e.to_string())?;
+    let mut endpoints: Vec<String> = LEADER
+        .lock()
+        .ok()
+        .and_then(|x| x.clone())
+        .into_iter()
+        .collect();
+    for endpoint in config.endpoints.iter().chain([&config.endpoint]) {
+        if !endpoints.contains(endpoint) {
+            endpoints.push(endpoint.to_string());
+        }
+    }
+
+    let mut redirect: Option<String> = None;
+    let (mut tried, mut unreachable) = (0, 0);
+    let mut error = String::new();
+
+    for _ in 0..MASTER_ATTEMPTS {
+        let base = redirect.take().unwrap_or_else(|| {
+            tried += 1;
+            endpoints[(tried - 1) % endpoints.len()].to_string()
+        });
+
+        match self::dispatch(config, &format!("{}/{}", base, route), &data, None).map_err(|e| *e) {
+            // redirects aren't followed by the agent and come back as a response
+            Ok(response) if response.status() == 307 => {
+                redirect = response
+                    .header("location")
+                    .and_then(|x| x.strip_suffix(&format!("/{}", route)))
+                    .map(|x| x.to_string());
+                error = format!("{}: redirected without a leader", base);
+                unreachable = 0;
+            }
+            Ok(response) => {
+                if let Ok(mut leader) = LEADER.lock() {
+                    *leader = Some(base);
+                }
                 return Ok(response);
             }
+            Err(ureq::Error::Status(503, response)) if response.has("retry-after") => {
+                error = self::describe(ureq::Error::Status(503, response));
+                unreachable = 0;
+                std::thread::sleep(Duration::from_millis(500));
+            }
+            Err(e @ ureq::Error::Transport(_)) => {
+                error = e.to_string();
+                unreachable += 1;
+                if unreachable >= endpoints.len() {
+                    break;
+                }
+            }
+            Err(e) => return Err(self::describe(e)),
+        }
+    }
+    Err(error)
+}
+
+/// errors include the reason the node gave in the body, e.g which quota an upload would exceed
+fn describe(error: ureq::Error) -> String {
+    match error {
+        ureq::Error::Status(code, response) => {
+            let url = response.get_url().to_string();
+            match response.into_string() {
+                Ok(reason) if !reason.is_empty() => {
+                    format!("{}: status code {}: {}", url, code, reason)
+                }
+                _ => format!("{}: status code {}", url, code),
+            }
+        }
+        e => e.to_string(),
+    }
+}
+
+/// checks the proof against the cluster token and, on the master, the token store. None when only
+/// the master can tell
+fn check(config: &Config, proof: &Proof) -> Option<Verdict> {
+    let key = proof.key();
+    if key == self::key_id(&config.token) {
+        return Some(proof.verify(&config.token, Identity::cluster()));
+    }
+
+    let store = TOKENS.lock().ok()?;
+    let tokens = store.as_ref()?;
+    Some(match tokens.iter().find(|x| x.key == key) {
+        Some(entry) => proof.verify(&self::derive(config, &entry.nonce), entry.identity()),
+        None => Verdict::Unknown,
+    })
+}
+
+/* -------------------------------------------------------------------------------------------------
+workers only know the cluster token, for any other token they hand the proof to the master which
+checks it and answers with the identity behind it (and never with a secret). A token sent as is can
+be remembered by its hash for a while, signatures are only good once so signed requests with named
+tokens are checked by the master every time.
+------------------------------------------------------------------------------------------------- */
+async fn authenticate(config: &Config, proof: Proof) -> Option<Identity> {
+    if let Some(verdict) = self::check(config, &proof) {
+        return verdict.identity();
+    }
+
+    let hash = match &proof {
+        Proof::Token(token) => Some(checksum::hash(token.as_bytes())),
+        Proof::Signed { .. } => None,
+    };
+    let lifetime = Duration::from_secs(TOKEN_CACHE_IN_SECONDS);
+    if let (Some(hash), Ok(cache)) = (&hash, TOKENCACHE.lock()) {
+        if let Some((identity, ts)) = cache.get(hash) {
+            if ts.elapsed() < lifetime {
+                return Some(identity.clone());
+            }
+        }
+    }
+
+    let config = config.clone();
+    let verdict: Verdict = tokio::task::spawn_blocking(move || {
+        self::send_master(&config, "introspect", Introspect { proof })
+            .ok()?
+            .into_json()
+            .ok()
+    })
+    .await
+    .ok()??;
+    let identity = verdict.identity()?;
+
+    if let (Some(hash), Ok(mut cache)) = (hash, TOKENCACHE.lock()) {
+        cache.retain(|_, x| x.1.elapsed() < lifetime);
+        cache.insert(hash, (identity.clone(), Instant::now()));
+    }
+    Some(identity)
+}
+
+/// loads the token store, only called by the master which makes it the authority for tokens
+pub fn load_tokens() {
+    let (tokens, hashed): (Vec<TokenEntry>, Vec<TokenEntry>) = fs::read_to_string(TOKEN_STORE)
+        .unwrap_or_default()
+        .lines()
+        .filter_map(|x| serde_json::from_str::<TokenEntry>(x).ok())
+        .partition(|x| !x.nonce.is_empty());
+    info!("loaded {} named token(s)", tokens.len());
+    if !hashed.is_empty() {
+        warn!(
+            "dropped {} token(s) stored as a hash by an older version, they have to be created again",
+            hashed.len()
+        );
+    }
+
+    if let Ok(mut store) = TOKENS.lock() {
+        *store = Some(tokens);
+    }
+}
+
+fn save_tokens(tokens: &[TokenEntry]) {
+    self::write_tokens(tokens);
+    raft::record(Op::Tokens(tokens.to_vec()));
+}
+
+fn write_tokens(tokens: &[TokenEntry]) {
+    let lines: Vec<String> = tokens
+        .iter()
+        .filter_map(|x| serde_json::to_string(x).ok())
+        .collect();
+
+    if let Err(e) = fs::write(TOKEN_STORE, lines.join("\n") + "\n") {
+        warn!("unable to save the token store: {}", e);
+    }
+}
+
+pub fn tokens() -> Vec<TokenEntry> {
+    TOKENS
+        .lock()
+        .ok()
+        .and_then(|x| x.clone())
+        .unwrap_or_default()
+}
+
+/// takes over the token store replicated from the leader
+pub fn replace_tokens(tokens: Vec<TokenEntry>) {
+    self::write_tokens(&tokens);
+    if let Ok(mut store) = TOKENS.lock() {
+        *store = Some(tokens);
+    }
+}
+
+pub async fn create_token(
+    State(config): State<Config>,
+    Json(payload): Json<TokenRequest>,
+) -> Response {
+    if payload.name.is_empty() || payload.name == "cluster" || payload.scopes.is_empty() {
+        return StatusCode::BAD_REQUEST.into_response();
+    }
+
+    let nonce = format!("{:032x}", rand::random::<u128>());
+    let token = self::derive(&config, &nonce);
+
+    if let Ok(mut store) = TOKENS.lock() {
+        let Some(tokens) = store.as_mut() else {
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        };
+        if tokens.iter().any(|x| x.name == payload.name) {
+            return StatusCode::CONFLICT.into_response();
+        }
+
+        tokens.push(TokenEntry {
+            name: payload.name.to_string(),
+            key: self::key_id(&token),
+            nonce,
+            scopes: payload.scopes.clone(),
+            groups: payload.groups.clone(),
+        });
+        self::save_tokens(tokens);
+    }
+
+    info!("created token [{}] with {:?}", payload.name, payload.scopes);
+    Json(NewToken {
+        name: payload.name,
+        token,
+        scopes: payload.scopes,
+    })
+    .into_response()
+}
+
+/// workers may keep trusting a revoked token for up to TOKEN_CACHE_IN_SECONDS
+pub async fn revoke_token(Json(payload): Json<TokenRequest>) -> Response {
+    if let Ok(mut store) = TOKENS.lock() {
+        if let Some(tokens) = store.as_mut() {
+            let before = tokens.len();
+            tokens.retain(|x| x.name != payload.name);
+
+            if tokens.len() < before {
+                self::save_tokens(tokens);
+                info!("revoked token [{}]", payload.name);
+                return Json(payload).into_response();
+            }
         }
     }
-    Err(StatusCode::UNAUTHORIZED)
+    StatusCode::NOT_FOUND.into_response()
+}
+
+pub async fn list_tokens() -> Response {
+    if let Ok(store) = TOKENS.lock() {
+        if let Some(tokens) = store.as_ref() {
+            let tokens: Vec<Identity> = tokens.iter().map(|x| x.identity()).collect();
+            return Json(tokens).into_response();
+        }
+    }
+    StatusCode::INTERNAL_SERVER_ERROR.into_response()
+}
+
+/// lets the workers check a token they don't know, the answer is the identity and never a secret
+pub async fn introspect(State(config): State<Config>, Json(payload): Json<Introspect>) -> Response {
+    Json(self::check(&config, &payload.proof).unwrap_or(Verdict::Unknown)).into_response()
 }
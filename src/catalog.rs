@@ -0,0 +1,214 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+use crate::master::MetaStore;
+
+/// a chunk is identified by the file it belongs to and its index within that file
+pub type Key = (String, i32);
+
+/// where the master keeps the metadata of its chunks, either in memory (`Catalog`) or on disk
+/// (`database::Database`)
+pub trait Backend: Send + Sync {
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// visits every chunk in order of its file and index
+    fn each(&self, visit: &mut dyn FnMut(&MetaStore));
+
+    fn get(&self, key: &Key) -> Option<MetaStore>;
+
+    /// adds the chunk, replacing the one with the same file and index
+    fn insert(&mut self, chunk: MetaStore);
+
+    fn extend(&mut self, chunks: Vec<MetaStore>) {
+        for chunk in chunks {
+            self.insert(chunk);
+        }
+    }
+
+    /// drops every chunk of the file, returning them in order
+    fn remove_file(&mut self, file_id: &str) -> Vec<MetaStore>;
+
+    fn clear(&mut self);
+
+    /// every file name, in order
+    fn names(&self) -> Vec<String>;
+
+    /// the ids of the files uploaded under the name
+    fn file_ids(&self, name: &str) -> Vec<String>;
+
+    /// the chunks of a single upload in order
+    fn file(&self, file_id: &str) -> Vec<MetaStore>;
+
+    /// the chunks of every upload under the name
+    fn named(&self, name: &str) -> Vec<MetaStore> {
+        self.file_ids(name)
+            .iter()
+            .flat_map(|x| self.file(x))
+            .collect()
+    }
+
+    /// how many chunks reference the chunk name
+    fn references(&self, name: &str) -> usize;
+
+    /// any of the chunks referencing the chunk name
+    fn stored(&self, name: &str) -> Option<MetaStore>;
+
+    /// the chunks stored as the replica or shard called `name` on the host
+    fn placed(&self, ip: &str, name: &str) -> Vec<Key>;
+
+    /// true when the chunks survive a restart by themselves, without the snapshot
+    fn durable(&self) -> bool {
+        false
+    }
+}
+
+/* -------------------------------------------------------------------------------------------------
+the metadata of every chunk, indexed so that the master never has to scan (or clone) all of it to
+answer a request: chunks are kept in order of their file and index, with indexes by file name, by
+the name the chunk is stored under on the workers and by the hosts holding a replica or shard. The
+indexes are only ever changed through `insert` and `remove_file`, so they can't drift apart.
+------------------------------------------------------------------------------------------------- */
+#[derive(Default)]
+pub struct Catalog {
+    chunks: BTreeMap<Key, MetaStore>,
+    /// the ids of the files uploaded under each name
+    files: BTreeMap<String, BTreeSet<String>>,
+    /// the chunks referencing each chunk name, i.e its reference count
+    names: HashMap<String, BTreeSet<Key>>,
+    /// the (stored) name and chunk of every replica or shard on each host
+    hosts: HashMap<String, BTreeSet<(String, Key)>>,
+}
+
+impl Catalog {
+    pub fn new(chunks: Vec<MetaStore>) -> Catalog {
+        let mut catalog = Catalog::default();
+        catalog.extend(chunks);
+        catalog
+    }
+
+    fn unindex(&mut self, key: &Key, chunk: &MetaStore) {
+        // other chunks of the same file may still be there
+        let last = self.chunks_of(&chunk.file_id).next().is_none();
+        if let Some(ids) = self.files.get_mut(&chunk.file_name) {
+            if last {
+                ids.remove(&chunk.file_id);
+            }
+            if ids.is_empty() {
+                self.files.remove(&chunk.file_name);
+            }
+        }
+        let name = chunk.chunk_name();
+        if let Some(keys) = self.names.get_mut(&name) {
+            keys.remove(key);
+            if keys.is_empty() {
+                self.names.remove(&name);
+            }
+        }
+        for (name, host) in chunk.placements() {
+            if let Some(placed) = self.hosts.get_mut(&host.ip) {
+                placed.remove(&(name, key.clone()));
+                if placed.is_empty() {
+                    self.hosts.remove(&host.ip);
+                }
+            }
+        }
+    }
+
+    fn chunks_of(&self, file_id: &str) -> impl Iterator<Item = (&Key, &MetaStore)> {
+        let from = (file_id.to_string(), i32::MIN);
+        let to = (file_id.to_string(), i32::MAX);
+        self.chunks.range(from..=to)
+    }
+}
+
+impl Backend for Catalog {
+    fn len(&self) -> usize {
+        self.chunks.len()
+    }
+
+    fn each(&self, visit: &mut dyn FnMut(&MetaStore)) {
+        self.chunks.values().for_each(visit);
+    }
+
+    fn get(&self, key: &Key) -> Option<MetaStore> {
+        self.chunks.get(key).cloned()
+    }
+
+    fn insert(&mut self, chunk: MetaStore) {
+        let key = (chunk.file_id.to_string(), chunk.chunk_id);
+        if let Some(previous) = self.chunks.remove(&key) {
+            self.unindex(&key, &previous);
+        }
+
+        self.files
+            .entry(chunk.file_name.to_string())
+            .or_default()
+            .insert(chunk.file_id.to_string());
+        self.names
+            .entry(chunk.chunk_name())
+            .or_default()
+            .insert(key.clone());
+        for (name, host) in chunk.placements() {
+            self.hosts
+                .entry(host.ip)
+                .or_default()
+                .insert((name, key.clone()));
+        }
+        self.chunks.insert(key, chunk);
+    }
+
+    fn remove_file(&mut self, file_id: &str) -> Vec<MetaStore> {
+        let keys: Vec<Key> = self.chunks_of(file_id).map(|x| x.0.clone()).collect();
+        let mut removed = vec![];
+        for key in keys {
+            if let Some(chunk) = self.chunks.remove(&key) {
+                self.unindex(&key, &chunk);
+                removed.push(chunk);
+            }
+        }
+        removed
+    }
+
+    fn clear(&mut self) {
+        *self = Catalog::default();
+    }
+
+    fn names(&self) -> Vec<String> {
+        self.files.keys().cloned().collect()
+    }
+
+    fn file_ids(&self, name: &str) -> Vec<String> {
+        self.files
+            .get(name)
+            .map(|x| x.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    fn file(&self, file_id: &str) -> Vec<MetaStore> {
+        self.chunks_of(file_id).map(|x| x.1.clone()).collect()
+    }
+
+    fn references(&self, name: &str) -> usize {
+        self.names.get(name).map(|x| x.len()).unwrap_or(0)
+    }
+
+    fn stored(&self, name: &str) -> Option<MetaStore> {
+        let key = self.names.get(name)?.first()?;
+        self.chunks.get(key).cloned()
+    }
+
+    fn placed(&self, ip: &str, name: &str) -> Vec<Key> {
+        let Some(placed) = self.hosts.get(ip) else {
+            return vec![];
+        };
+        let from = (name.to_string(), (String::new(), i32::MIN));
+        placed
+            .range(from..)
+            .take_while(|x| x.0 == name)
+            .map(|x| x.1.clone())
+            .collect()
+    }
+}
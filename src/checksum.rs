@@ -0,0 +1,70 @@
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// extension of the sidecar file holding the checksums next to each chunk
+pub const EXTENSION: &str = "crc";
+
+/// chunks are checksummed in fixed size blocks so that corruption can be pinned to a region
+pub const BLOCK_SIZE: usize = 64 * 1024;
+
+#[derive(Debug)]
+pub enum Integrity {
+    /// no sidecar exists, e.g the chunk was written before checksums were introduced
+    Missing,
+    /// the block at the given index does not match its stored checksum
+    Corrupt(usize),
+}
+
+pub fn sidecar(chunk: &Path) -> PathBuf {
+    let mut path = chunk.as_os_str().to_owned();
+    path.push(format!(".{}", EXTENSION));
+    PathBuf::from(path)
+}
+
+/// the content hash (hex encoded SHA-256) used for both whole files and single chunks
+pub fn hash(data: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(data))
+}
+
+/// a content hash is only accepted when it looks like something `hash` could have produced
+pub fn is_hash(value: &str) -> bool {
+    value.len() == 64 && value.chars().all(|x| matches!(x, '0'..='9' | 'a'..='f'))
+}
+
+pub fn compute(data: &[u8]) -> Vec<u32> {
+    data.chunks(BLOCK_SIZE).map(crc32c::crc32c).collect()
+}
+
+pub fn store(chunk: &Path, data: &[u8]) -> Result<(), io::Error> {
+    let sums = serde_json::to_string(&compute(data))?;
+    fs::write(sidecar(chunk), sums)
+}
+
+pub fn verify(chunk: &Path, data: &[u8]) -> Result<(), Integrity> {
+    let path = sidecar(chunk);
+
+    if !path.exists() {
+        return Err(Integrity::Missing);
+    }
+
+    let expected: Vec<u32> = fs::read_to_string(&path)
+        .ok()
+        .and_then(|x| serde_json::from_str(&x).ok())
+        .ok_or(Integrity::Corrupt(0))?;
+
+    let actual = compute(data);
+
+    for (block, sum) in actual.iter().enumerate() {
+        if expected.get(block) != Some(sum) {
+            return Err(Integrity::Corrupt(block));
+        }
+    }
+
+    if expected.len() != actual.len() {
+        return Err(Integrity::Corrupt(actual.len()));
+    }
+
+    Ok(())
+}
@@ -1,4 +1,28 @@
-use tracing::info;
+use crate::acl;
+use crate::acl::{Acl, ChmodRequest, ChownRequest};
+use crate::audit::{AuditQuery, Event};
+use crate::auth;
+use crate::auth::{Identity, NewToken, TokenRequest, MAX_SHARE_IN_SECONDS};
+use crate::checksum;
+use crate::compression;
+use crate::compression::Codec;
+use crate::config;
+use crate::config::Config;
+use crate::encryption;
+use crate::erasure;
+use crate::master::{
+    FileMeta, FileUploadMeta, MetaStore, Policy, ShareLink, UploadPlan, FILE_CHUNK_SIZE,
+};
+use crate::quota;
+use crate::quota::{Quota, Usage, UsageQuery};
+use crate::snapshot::{Snapshot, SnapshotRequest};
+use crate::worker;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::json;
+use std::fs;
+use tracing::{error, info, warn};
+use url::Url;
 
 pub fn list(path: &Option<String>) {
     info!(
@@ -8,11 +32,202 @@ pub fn list(path: &Option<String>) {
 }
 
 pub fn get(file: &String) {
-    info!("todo: get remote objects based on file name '{}'...", file);
+    let Some(config) = config::get() else {
+        error!("unable able to load the valid cluster configuration. Please make sure the ENV 'RDFS_ENDPOINT' and 'RDFS_TOKEN' are set");
+        return;
+    };
+
+    let meta = FileMeta {
+        name: file.to_string(),
+    };
+    let mut chunks: Vec<MetaStore> = match self::post(&config, "get", meta) {
+        Ok(chunks) => chunks,
+        Err(e) => {
+            error!("unable to get remote file '{}': {}", file, e);
+            return;
+        }
+    };
+    chunks.sort_by_key(|x| x.chunk_id);
+
+    // every chunk of a file carries the wrapped data key of the file
+    let mut key: Option<Vec<u8>> = None;
+    if let Some(wrapped) = chunks.first().map(|x| &x.wrapped_key) {
+        if !wrapped.is_empty() {
+            key = self::load_key(&config).and_then(|x| encryption::unwrap(&x, wrapped));
+            if key.is_none() {
+                error!(
+                    "unable to unwrap the data key of '{}' with the keyfile",
+                    file
+                );
+                return;
+            }
+        }
+    }
+
+    let mut data: Vec<u8> = Vec::new();
+
+    for chunk in chunks.iter() {
+        let plain = worker::fetch_chunk(chunk, &config).and_then(|x| match &key {
+            None => Some(x),
+            Some(key) => encryption::decrypt(key, &encryption::chunk_nonce(chunk.chunk_id), &x),
+        });
+        match plain {
+            Some(bytes) => data.extend(bytes),
+            None => {
+                error!(
+                    "no replica could serve a valid copy of chunk {}",
+                    chunk.chunk_id
+                );
+                return;
+            }
+        }
+    }
+
+    // every chunk of a file carries the hash of the whole file
+    if let Some(chunk) = chunks.first() {
+        if checksum::hash(&data) != chunk.hash {
+            error!("remote file '{}' does not match its hash", file);
+            return;
+        }
+    }
+
+    match fs::write(file, data) {
+        Ok(_) => info!("remote file '{}' downloaded", file),
+        Err(e) => error!("unable to write file '{}': {}", file, e),
+    }
 }
 
-pub fn add(file: &String) {
-    info!("todo: add remote objects based on file name '{}'...", file);
+pub fn add(file: &String, erasure: &Option<String>, compress: &Option<String>, encrypt: bool) {
+    let Some(config) = config::get() else {
+        error!("unable able to load the valid cluster configuration. Please make sure the ENV 'RDFS_ENDPOINT' and 'RDFS_TOKEN' are set");
+        return;
+    };
+
+    let policy = match erasure {
+        None => Policy::Replicated,
+        Some(x) => match erasure::parse(x) {
+            Some((data, parity)) => Policy::Erasure { data, parity },
+            None => {
+                error!("invalid erasure coding policy '{}', expected e.g 6+3", x);
+                return;
+            }
+        },
+    };
+
+    let codec = match compress {
+        None => Codec::None,
+        Some(x) => match compression::parse(x) {
+            Some(codec) => codec,
+            None => {
+                error!("unknown compression codec '{}', expected zstd or lz4", x);
+                return;
+            }
+        },
+    };
+
+    let data = match fs::read(file) {
+        Ok(data) => data,
+        Err(e) => {
+            error!("unable to read file '{}': {}", file, e);
+            return;
+        }
+    };
+
+    let mut parts: Vec<Vec<u8>> = data
+        .chunks(FILE_CHUNK_SIZE as usize)
+        .map(|x| x.to_vec())
+        .collect();
+    if parts.is_empty() {
+        parts.push(vec![]);
+    }
+
+    /* ---------------------------------------------------------------------------------------------
+    envelope encryption: every file gets its own random data key that encrypts the chunks, the data
+    key itself is wrapped by the key in the local keyfile and only the wrapped key is handed to the
+    master. The workers never see any plaintext.
+    --------------------------------------------------------------------------------------------- */
+    let mut wrapped_key = String::new();
+    if encrypt {
+        let Some(kek) = self::load_key(&config) else {
+            error!("unable to load the keyfile, please make sure the ENV 'RDFS_KEYFILE' is set");
+            return;
+        };
+        let key = encryption::generate_key();
+        let encrypted: Option<Vec<Vec<u8>>> = (1..)
+            .zip(parts.iter())
+            .map(|(i, x)| encryption::encrypt(&key, &encryption::chunk_nonce(i), x))
+            .collect();
+        match (encrypted, encryption::wrap(&kek, &key)) {
+            (Some(encrypted), Some(wrapped)) => {
+                parts = encrypted;
+                wrapped_key = wrapped;
+            }
+            _ => {
+                error!("unable to encrypt file '{}'", file);
+                return;
+            }
+        }
+    }
+
+    // erasure coding happens on the client so that only the shards travel over the network
+    let shards: Vec<Vec<Vec<u8>>> = match policy {
+        Policy::Replicated => vec![],
+        Policy::Erasure { data, parity } => parts
+            .iter()
+            .filter_map(|x| erasure::encode(x, data, parity))
+            .collect(),
+    };
+
+    let meta = FileUploadMeta {
+        name: file.to_string(),
+        hash: checksum::hash(&data),
+        size: data.len() as u64,
+        chunks: parts.iter().map(|x| checksum::hash(x)).collect(),
+        policy,
+        codec,
+        shards: shards
+            .iter()
+            .map(|x| x.iter().map(|y| checksum::hash(y)).collect())
+            .collect(),
+        wrapped_key,
+    };
+
+    let plan: UploadPlan = match self::post(&config, "upload", meta) {
+        Ok(plan) => plan,
+        Err(e) => {
+            error!("unable to upload file '{}': {}", file, e);
+            return;
+        }
+    };
+
+    for warning in plan.warnings.iter() {
+        warn!("{}", warning);
+    }
+
+    // chunks with content the cluster already holds don't need to be sent again
+    for chunk in plan.pending.iter() {
+        let index = (chunk.chunk_id - 1) as usize;
+        let Some(part) = parts.get(index) else {
+            continue;
+        };
+        for (i, (name, host)) in chunk.placements().iter().enumerate() {
+            let bytes = match chunk.policy {
+                Policy::Replicated => part,
+                Policy::Erasure { .. } => &shards[index][i],
+            };
+            let capability = plan.capabilities.get(name);
+            if !worker::push_chunk(&host.ip, name, bytes, chunk.codec, capability, &config) {
+                warn!("unable to store chunk {} on {}", chunk.chunk_id, host.ip);
+            }
+        }
+    }
+
+    info!(
+        "file '{}' uploaded, {} new chunk(s) sent and {} already stored",
+        file,
+        plan.pending.len(),
+        plan.existing.len()
+    );
 }
 
 pub fn remove(file: &String) {
@@ -21,3 +236,395 @@ pub fn remove(file: &String) {
         file
     );
 }
+
+/// mints a link anyone can download the file with until it expires, e.g `--expires 1h`
+pub fn share(file: &str, expires: &str) {
+    let Some(config) = config::get() else {
+        error!("unable able to load the valid cluster configuration. Please make sure the ENV 'RDFS_ENDPOINT' and 'RDFS_TOKEN' are set");
+        return;
+    };
+
+    let Some(seconds) =
+        self::parse_duration(expires).filter(|x| *x > 0 && *x <= MAX_SHARE_IN_SECONDS)
+    else {
+        error!(
+            "invalid expiry '{}', use e.g 30m, 12h or 7d (at most a week)",
+            expires
+        );
+        return;
+    };
+
+    let meta = FileMeta {
+        name: file.to_string(),
+    };
+    match self::post::<_, Vec<MetaStore>>(&config, "get", meta) {
+        Ok(chunks) if chunks.iter().any(|x| !x.wrapped_key.is_empty()) => {
+            error!("encrypted file '{}' can't be shared", file);
+            return;
+        }
+        Ok(_) => {}
+        Err(e) => {
+            error!("unable to get remote file '{}': {}", file, e);
+            return;
+        }
+    }
+
+    let expires = chrono::Utc::now().timestamp() + seconds;
+    let (key, signature) = auth::sign_share(&config.token, file, expires);
+    let link = ShareLink {
+        file: file.to_string(),
+        expires,
+        key,
+        signature,
+    };
+    let query = [
+        ("file", link.file),
+        ("expires", link.expires.to_string()),
+        ("key", link.key),
+        ("signature", link.signature),
+    ];
+    match Url::parse_with_params(&format!("{}/share", config.endpoint), query) {
+        Ok(url) => println!("{}", url),
+        Err(e) => error!("unable to build the share link: {}", e),
+    }
+}
+
+/// parses durations like 90s, 30m, 12h or 7d into seconds
+fn parse_duration(value: &str) -> Option<i64> {
+    let (amount, unit) = value.split_at(value.find(|x: char| !x.is_ascii_digit())?);
+    let amount: i64 = amount.parse().ok()?;
+    match unit {
+        "s" => Some(amount),
+        "m" => Some(amount * 60),
+        "h" => Some(amount * 60 * 60),
+        "d" => Some(amount * 24 * 60 * 60),
+        _ => None,
+    }
+}
+
+pub fn create_token(name: &str, scopes: &[String], groups: &[String]) {
+    let Some(config) = config::get() else {
+        error!("unable able to load the valid cluster configuration. Please make sure the ENV 'RDFS_ENDPOINT' and 'RDFS_TOKEN' are set");
+        return;
+    };
+
+    let Some(scopes) = scopes
+        .iter()
+        .map(|x| auth::parse_scope(x))
+        .collect::<Option<Vec<_>>>()
+    else {
+        error!("invalid scope, allowed values are \"read\", \"write\", \"admin\" or \"worker\"");
+        return;
+    };
+
+    let request = TokenRequest {
+        name: name.to_string(),
+        scopes,
+        groups: groups.to_vec(),
+    };
+    match self::post::<_, NewToken>(&config, "create-token", request) {
+        Ok(token) => println!("{}", token.token),
+        Err(e) => error!("unable to create token '{}': {}", name, e),
+    }
+}
+
+pub fn revoke_token(name: &str) {
+    let Some(config) = config::get() else {
+        error!("unable able to load the valid cluster configuration. Please make sure the ENV 'RDFS_ENDPOINT' and 'RDFS_TOKEN' are set");
+        return;
+    };
+
+    let request = TokenRequest {
+        name: name.to_string(),
+        scopes: vec![],
+        groups: vec![],
+    };
+    match self::post::<_, TokenRequest>(&config, "revoke-token", request) {
+        Ok(_) => info!("revoked token '{}'", name),
+        Err(e) => error!("unable to revoke token '{}': {}", name, e),
+    }
+}
+
+pub fn list_tokens() {
+    let Some(config) = config::get() else {
+        error!("unable able to load the valid cluster configuration. Please make sure the ENV 'RDFS_ENDPOINT' and 'RDFS_TOKEN' are set");
+        return;
+    };
+
+    match self::post::<_, Vec<Identity>>(&config, "list-tokens", json!({})) {
+        Ok(tokens) => {
+            for token in tokens {
+                println!("{}\t{:?}", token.name, token.scopes);
+            }
+        }
+        Err(e) => error!("unable to list tokens: {}", e),
+    }
+}
+
+/// grants a permission on a file or directory to `user:<token name>` or `group:<group name>`
+pub fn chmod(path: &str, principal: &str, permission: &str) {
+    let Some(config) = config::get() else {
+        error!("unable able to load the valid cluster configuration. Please make sure the ENV 'RDFS_ENDPOINT' and 'RDFS_TOKEN' are set");
+        return;
+    };
+
+    let Some(permission) = acl::parse_permission(permission) else {
+        error!("invalid permission, allowed values are \"read\", \"write\", \"admin\" or \"none\"");
+        return;
+    };
+
+    let request = ChmodRequest {
+        path: path.to_string(),
+        principal: principal.to_string(),
+        permission,
+    };
+    match self::post::<_, Acl>(&config, "chmod", request) {
+        Ok(acl) => self::print_acl(&acl),
+        Err(e) => error!("unable to change the permissions of '{}': {}", path, e),
+    }
+}
+
+pub fn chown(path: &str, owner: &str) {
+    let Some(config) = config::get() else {
+        error!("unable able to load the valid cluster configuration. Please make sure the ENV 'RDFS_ENDPOINT' and 'RDFS_TOKEN' are set");
+        return;
+    };
+
+    let request = ChownRequest {
+        path: path.to_string(),
+        owner: owner.to_string(),
+    };
+    match self::post::<_, Acl>(&config, "chown", request) {
+        Ok(acl) => self::print_acl(&acl),
+        Err(e) => error!("unable to change the owner of '{}': {}", path, e),
+    }
+}
+
+fn print_acl(acl: &Acl) {
+    println!("/{}\towner: {}", acl.path, acl.owner);
+    for grant in acl.grants.iter() {
+        println!("\t{}: {:?}", grant.principal, grant.permission);
+    }
+}
+
+/// prints the audit events matching all of the given filters, oldest first
+pub fn audit(
+    user: &Option<String>,
+    path: &Option<String>,
+    since: &Option<String>,
+    until: &Option<String>,
+) {
+    let Some(config) = config::get() else {
+        error!("unable able to load the valid cluster configuration. Please make sure the ENV 'RDFS_ENDPOINT' and 'RDFS_TOKEN' are set");
+        return;
+    };
+
+    let mut query = AuditQuery {
+        user: user.clone(),
+        path: path.clone(),
+        ..Default::default()
+    };
+    for (value, bound) in [(since, &mut query.since), (until, &mut query.until)] {
+        let Some(value) = value else {
+            continue;
+        };
+        match self::parse_time(value) {
+            Some(x) => *bound = Some(x),
+            None => {
+                error!(
+                    "invalid time '{}', use e.g 2024-10-01T00:00:00Z or 24h",
+                    value
+                );
+                return;
+            }
+        }
+    }
+
+    match self::post::<_, Vec<Event>>(&config, "audit", query) {
+        Ok(events) => {
+            for event in events {
+                let time = chrono::DateTime::from_timestamp(event.timestamp, 0)
+                    .map(|x| x.to_rfc3339())
+                    .unwrap_or_default();
+                println!(
+                    "{}\t{}\t{}\t{}\t{}\t{}",
+                    time, event.identity, event.source, event.operation, event.target, event.status
+                );
+            }
+        }
+        Err(e) => error!("unable to query the audit log: {}", e),
+    }
+}
+
+/// parses either a RFC 3339 time or a duration (see `parse_duration`) meaning that long ago
+fn parse_time(value: &str) -> Option<i64> {
+    match chrono::DateTime::parse_from_rfc3339(value) {
+        Ok(time) => Some(time.timestamp()),
+        Err(_) => Some(chrono::Utc::now().timestamp() - self::parse_duration(value)?),
+    }
+}
+
+/// shows the usage and quotas of the caller (or one subject), admins see every owner
+pub fn quota(subject: &Option<String>) {
+    let Some(config) = config::get() else {
+        error!("unable able to load the valid cluster configuration. Please make sure the ENV 'RDFS_ENDPOINT' and 'RDFS_TOKEN' are set");
+        return;
+    };
+
+    let query = UsageQuery {
+        subject: subject.clone(),
+    };
+    match self::post::<_, Vec<Usage>>(&config, "quota", query) {
+        Ok(report) => {
+            let limit = |x: Option<u64>| x.map(quota::format_size).unwrap_or(String::from("-"));
+            for usage in report {
+                println!(
+                    "{}\tlogical: {}\tphysical: {}\tsoft: {}\thard: {}",
+                    usage.subject,
+                    quota::format_size(usage.logical),
+                    quota::format_size(usage.physical),
+                    limit(usage.soft),
+                    limit(usage.hard)
+                );
+            }
+        }
+        Err(e) => error!("unable to get the quotas: {}", e),
+    }
+}
+
+/// sets the limits on the physical bytes of `user:<owner>` or `dir:<path>`, no limits removes them
+pub fn set_quota(subject: &str, soft: &Option<String>, hard: &Option<String>) {
+    let Some(config) = config::get() else {
+        error!("unable able to load the valid cluster configuration. Please make sure the ENV 'RDFS_ENDPOINT' and 'RDFS_TOKEN' are set");
+        return;
+    };
+
+    let mut request = Quota {
+        subject: subject.to_string(),
+        soft: None,
+        hard: None,
+    };
+    for (value, limit) in [(soft, &mut request.soft), (hard, &mut request.hard)] {
+        let Some(value) = value else {
+            continue;
+        };
+        match quota::parse_size(value) {
+            Some(x) => *limit = Some(x),
+            None => {
+                error!("invalid size '{}', use e.g 500M, 10G or 2T", value);
+                return;
+            }
+        }
+    }
+
+    match self::post::<_, Quota>(&config, "set-quota", request) {
+        Ok(quota) if quota.soft.is_none() && quota.hard.is_none() => {
+            println!("{}\tno quota", quota.subject)
+        }
+        Ok(quota) => println!(
+            "{}\tsoft: {}\thard: {}",
+            quota.subject,
+            quota
+                .soft
+                .map(quota::format_size)
+                .unwrap_or(String::from("-")),
+            quota
+                .hard
+                .map(quota::format_size)
+                .unwrap_or(String::from("-"))
+        ),
+        Err(e) => error!("unable to set the quota of {}: {}", subject, e),
+    }
+}
+
+/// takes a snapshot of the directory, which copies none of the data
+pub fn create_snapshot(path: &str, name: &str) {
+    let Some(config) = config::get() else {
+        error!("unable able to load the valid cluster configuration. Please make sure the ENV 'RDFS_ENDPOINT' and 'RDFS_TOKEN' are set");
+        return;
+    };
+
+    let request = SnapshotRequest {
+        name: name.to_string(),
+        path: path.to_string(),
+    };
+    match self::post::<_, Snapshot>(&config, "create-snapshot", request) {
+        Ok(snapshot) => self::print_snapshot(&snapshot),
+        Err(e) => error!("unable to take snapshot '{}' of '{}': {}", name, path, e),
+    }
+}
+
+pub fn list_snapshots() {
+    let Some(config) = config::get() else {
+        error!("unable able to load the valid cluster configuration. Please make sure the ENV 'RDFS_ENDPOINT' and 'RDFS_TOKEN' are set");
+        return;
+    };
+
+    match self::post::<_, Vec<Snapshot>>(&config, "list-snapshots", json!({})) {
+        Ok(snapshots) => {
+            for snapshot in snapshots.iter() {
+                self::print_snapshot(snapshot);
+            }
+        }
+        Err(e) => error!("unable to list the snapshots: {}", e),
+    }
+}
+
+/// puts the directory of the snapshot back the way it was, files added since are removed
+pub fn restore_snapshot(name: &str) {
+    let Some(config) = config::get() else {
+        error!("unable able to load the valid cluster configuration. Please make sure the ENV 'RDFS_ENDPOINT' and 'RDFS_TOKEN' are set");
+        return;
+    };
+
+    let request = SnapshotRequest {
+        name: name.to_string(),
+        ..Default::default()
+    };
+    match self::post::<_, Snapshot>(&config, "restore-snapshot", request) {
+        Ok(snapshot) => info!("restored '/{}' from snapshot '{}'", snapshot.dir, name),
+        Err(e) => error!("unable to restore snapshot '{}': {}", name, e),
+    }
+}
+
+pub fn delete_snapshot(name: &str) {
+    let Some(config) = config::get() else {
+        error!("unable able to load the valid cluster configuration. Please make sure the ENV 'RDFS_ENDPOINT' and 'RDFS_TOKEN' are set");
+        return;
+    };
+
+    let request = SnapshotRequest {
+        name: name.to_string(),
+        ..Default::default()
+    };
+    match self::post::<_, Snapshot>(&config, "delete-snapshot", request) {
+        Ok(_) => info!("deleted snapshot '{}'", name),
+        Err(e) => error!("unable to delete snapshot '{}': {}", name, e),
+    }
+}
+
+fn print_snapshot(snapshot: &Snapshot) {
+    let created = chrono::DateTime::from_timestamp(snapshot.created, 0)
+        .map(|x| x.to_rfc3339())
+        .unwrap_or_default();
+    println!(
+        "{}\t/{}\t{}\t{} file(s)\towner: {}",
+        snapshot.name, snapshot.dir, created, snapshot.files, snapshot.owner
+    );
+}
+
+fn load_key(config: &Config) -> Option<Vec<u8>> {
+    encryption::load_key(config.keyfile.as_ref()?)
+}
+
+/// sends an authorised json request to either the master or a worker node
+fn post<T: Serialize, R: DeserializeOwned>(
+    config: &Config,
+    route: &str,
+    data: T,
+) -> Result<R, String> {
+    auth::send_master(config, route, data)
+        .map_err(|e| e.to_string())?
+        .into_json()
+        .map_err(|e| e.to_string())
+}
@@ -0,0 +1,40 @@
+use serde::{Deserialize, Serialize};
+use std::io;
+
+const ZSTD_LEVEL: i32 = 3;
+
+/// how a chunk is compressed on the worker's disk, hashes are always over the raw bytes
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, Default, PartialEq)]
+pub enum Codec {
+    #[default]
+    None,
+    Zstd,
+    Lz4,
+}
+
+pub fn compress(codec: Codec, data: &[u8]) -> Result<Vec<u8>, io::Error> {
+    match codec {
+        Codec::None => Ok(data.to_vec()),
+        Codec::Zstd => zstd::encode_all(data, ZSTD_LEVEL),
+        Codec::Lz4 => Ok(lz4_flex::compress_prepend_size(data)),
+    }
+}
+
+pub fn decompress(codec: Codec, data: &[u8]) -> Result<Vec<u8>, io::Error> {
+    match codec {
+        Codec::None => Ok(data.to_vec()),
+        Codec::Zstd => zstd::decode_all(data),
+        Codec::Lz4 => lz4_flex::decompress_size_prepended(data)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+    }
+}
+
+/// parses the codec name given on the command line
+pub fn parse(codec: &str) -> Option<Codec> {
+    match codec.to_lowercase().as_ref() {
+        "none" => Some(Codec::None),
+        "zstd" => Some(Codec::Zstd),
+        "lz4" => Some(Codec::Lz4),
+        _ => None,
+    }
+}
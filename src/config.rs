@@ -1,16 +1,83 @@
 use std::env;
 
+/// default I/O budget of the background scrubber in bytes per second
+const DEFAULT_SCRUB_RATE: u64 = 1024 * 1024;
+/// how far apart (in seconds) the clocks of a signed request and the node may be
+const DEFAULT_CLOCK_SKEW: u64 = 5 * 60;
+
 #[derive(Clone)]
 pub struct Config {
+    /// the first of the master endpoints
     pub endpoint: String,
+    /// every master of the cluster, more than one enables high availability
+    pub endpoints: Vec<String>,
+    /// the endpoint (one of `endpoints`) the other masters reach this master on
+    pub advertise: Option<String>,
     pub token: String,
+    pub data_dir: String,
+    pub scrub_rate: u64,
+    pub keyfile: Option<String>,
+    pub node_key: Option<String>,
+    pub previous_node_key: Option<String>,
+    pub tls_cert: Option<String>,
+    pub tls_key: Option<String>,
+    pub tls_ca: Option<String>,
+    pub signed_requests: bool,
+    pub clock_skew: u64,
+    /// requests per second a single token may make to the node, unlimited when not set
+    pub request_rate: Option<u64>,
+    /// request and response bytes per second a single token may send to and get from the node
+    pub byte_rate: Option<u64>,
+    /// how many requests the node handles at once across all tokens
+    pub max_concurrent: Option<usize>,
+    /// keeps the metadata of the chunks in this database file instead of in memory
+    pub metadata_db: Option<String>,
 }
 
 pub fn get() -> Option<Config> {
     if let (Ok(x), Ok(y)) = (env::var("RDFS_ENDPOINT"), env::var("RDFS_TOKEN")) {
+        let endpoints: Vec<String> = x
+            .split(',')
+            .map(|x| x.trim().trim_end_matches('/').to_string())
+            .filter(|x| !x.is_empty())
+            .collect();
         return Some(Config {
-            endpoint: x,
+            endpoint: endpoints.first().cloned().unwrap_or(x),
+            endpoints,
+            advertise: env::var("RDFS_ADVERTISE")
+                .ok()
+                .map(|x| x.trim_end_matches('/').to_string()),
             token: y,
+            data_dir: env::var("RDFS_DATA_DIR").unwrap_or(String::from(".")),
+            scrub_rate: env::var("RDFS_SCRUB_RATE")
+                .ok()
+                .and_then(|x| x.parse().ok())
+                .filter(|x| *x > 0)
+                .unwrap_or(DEFAULT_SCRUB_RATE),
+            keyfile: env::var("RDFS_KEYFILE").ok(),
+            node_key: env::var("RDFS_NODE_KEY").ok(),
+            previous_node_key: env::var("RDFS_NODE_KEY_PREVIOUS").ok(),
+            tls_cert: env::var("RDFS_TLS_CERT").ok(),
+            tls_key: env::var("RDFS_TLS_KEY").ok(),
+            tls_ca: env::var("RDFS_TLS_CA").ok(),
+            signed_requests: env::var("RDFS_SIGNED_REQUESTS").is_ok_and(|x| x == "true"),
+            clock_skew: env::var("RDFS_CLOCK_SKEW")
+                .ok()
+                .and_then(|x| x.parse().ok())
+                .unwrap_or(DEFAULT_CLOCK_SKEW),
+            request_rate: env::var("RDFS_REQUEST_RATE")
+                .ok()
+                .and_then(|x| x.parse().ok())
+                .filter(|x| *x > 0),
+            byte_rate: env::var("RDFS_BYTE_RATE")
+                .ok()
+                .and_then(|x| x.parse().ok())
+                .filter(|x| *x > 0),
+            max_concurrent: env::var("RDFS_MAX_CONCURRENT")
+                .ok()
+                .and_then(|x| x.parse().ok())
+                .filter(|x| *x > 0),
+            metadata_db: env::var("RDFS_METADATA_DB").ok(),
         });
     }
     None
@@ -0,0 +1,256 @@
+// every call returns the errors of redb as they are, they only ever get logged
+#![allow(clippy::result_large_err)]
+
+use redb::{
+    MultimapTableDefinition, ReadTransaction, ReadableMultimapTable, ReadableTable,
+    ReadableTableMetadata, TableDefinition, WriteTransaction,
+};
+use tracing::error;
+
+use crate::catalog::{Backend, Key};
+use crate::master::MetaStore;
+
+/// every chunk as json, by file id and chunk index
+const CHUNKS: TableDefinition<(&str, i32), &[u8]> = TableDefinition::new("chunks");
+/// the ids of the files uploaded under each name
+const FILES: MultimapTableDefinition<&str, &str> = MultimapTableDefinition::new("files");
+/// the chunks referencing each chunk name
+const NAMES: MultimapTableDefinition<&str, (&str, i32)> = MultimapTableDefinition::new("names");
+/// the chunks stored as each replica or shard on a host, by host and stored name
+const HOSTS: MultimapTableDefinition<(&str, &str), (&str, i32)> =
+    MultimapTableDefinition::new("hosts");
+
+/* -------------------------------------------------------------------------------------------------
+keeps the metadata of the chunks in an embedded B-tree database on disk instead of in memory, for
+namespaces that no longer fit in RAM. It holds the same indexes as the `Catalog`, every change is a
+single transaction that is on disk once it returns, so a restart only has to open the file instead
+of replaying the snapshot.
+------------------------------------------------------------------------------------------------- */
+pub struct Database {
+    db: redb::Database,
+}
+
+impl Database {
+    pub fn open(path: &str) -> Result<Database, String> {
+        let db = redb::Database::create(path).map_err(|e| e.to_string())?;
+        let database = Database { db };
+        database
+            .write(|txn| {
+                txn.open_table(CHUNKS)?;
+                txn.open_multimap_table(FILES)?;
+                txn.open_multimap_table(NAMES)?;
+                txn.open_multimap_table(HOSTS)?;
+                Ok(())
+            })
+            .ok_or(format!("unable to set up the metadata database {}", path))?;
+        Ok(database)
+    }
+
+    fn write<T>(
+        &self,
+        change: impl FnOnce(&WriteTransaction) -> Result<T, redb::Error>,
+    ) -> Option<T> {
+        let result = self
+            .db
+            .begin_write()
+            .map_err(redb::Error::from)
+            .and_then(|txn| {
+                let value = change(&txn)?;
+                txn.commit()?;
+                Ok(value)
+            });
+        result
+            .map_err(|e| error!("unable to write to the metadata database: {}", e))
+            .ok()
+    }
+
+    fn read<T: Default>(
+        &self,
+        query: impl FnOnce(&ReadTransaction) -> Result<T, redb::Error>,
+    ) -> T {
+        self.db
+            .begin_read()
+            .map_err(redb::Error::from)
+            .and_then(|txn| query(&txn))
+            .map_err(|e| error!("unable to read from the metadata database: {}", e))
+            .unwrap_or_default()
+    }
+}
+
+fn decode(bytes: &[u8]) -> Option<MetaStore> {
+    serde_json::from_slice(bytes).ok()
+}
+
+fn put(txn: &WriteTransaction, chunk: &MetaStore) -> Result<(), redb::Error> {
+    let key = (chunk.file_id.as_str(), chunk.chunk_id);
+    let previous = txn
+        .open_table(CHUNKS)?
+        .insert(
+            key,
+            serde_json::to_vec(chunk).unwrap_or_default().as_slice(),
+        )?
+        .and_then(|x| self::decode(x.value()));
+    if let Some(previous) = previous {
+        self::unindex(txn, &previous)?;
+    }
+
+    txn.open_multimap_table(FILES)?
+        .insert(chunk.file_name.as_str(), chunk.file_id.as_str())?;
+    txn.open_multimap_table(NAMES)?
+        .insert(chunk.chunk_name().as_str(), key)?;
+    let mut hosts = txn.open_multimap_table(HOSTS)?;
+    for (name, host) in chunk.placements() {
+        hosts.insert((host.ip.as_str(), name.as_str()), key)?;
+    }
+    Ok(())
+}
+
+/// drops the chunk from the indexes, the file keeps its name as long as any of its chunks is left
+fn unindex(txn: &WriteTransaction, chunk: &MetaStore) -> Result<(), redb::Error> {
+    let key = (chunk.file_id.as_str(), chunk.chunk_id);
+    let left = txn
+        .open_table(CHUNKS)?
+        .range((chunk.file_id.as_str(), i32::MIN)..=(chunk.file_id.as_str(), i32::MAX))?
+        .next()
+        .is_some();
+    if !left {
+        txn.open_multimap_table(FILES)?
+            .remove(chunk.file_name.as_str(), chunk.file_id.as_str())?;
+    }
+    txn.open_multimap_table(NAMES)?
+        .remove(chunk.chunk_name().as_str(), key)?;
+    let mut hosts = txn.open_multimap_table(HOSTS)?;
+    for (name, host) in chunk.placements() {
+        hosts.remove((host.ip.as_str(), name.as_str()), key)?;
+    }
+    Ok(())
+}
+
+fn chunks_of(txn: &ReadTransaction, file_id: &str) -> Result<Vec<MetaStore>, redb::Error> {
+    let mut chunks = vec![];
+    for entry in txn
+        .open_table(CHUNKS)?
+        .range((file_id, i32::MIN)..=(file_id, i32::MAX))?
+    {
+        chunks.extend(self::decode(entry?.1.value()));
+    }
+    Ok(chunks)
+}
+
+impl Backend for Database {
+    fn len(&self) -> usize {
+        self.read(|txn| Ok(txn.open_table(CHUNKS)?.len()? as usize))
+    }
+
+    fn each(&self, visit: &mut dyn FnMut(&MetaStore)) {
+        self.read(|txn| {
+            for entry in txn.open_table(CHUNKS)?.iter()? {
+                if let Some(chunk) = self::decode(entry?.1.value()) {
+                    visit(&chunk);
+                }
+            }
+            Ok(())
+        })
+    }
+
+    fn get(&self, key: &Key) -> Option<MetaStore> {
+        self.read(|txn| {
+            let table = txn.open_table(CHUNKS)?;
+            let chunk = table.get((key.0.as_str(), key.1))?;
+            Ok(chunk.and_then(|x| self::decode(x.value())))
+        })
+    }
+
+    fn insert(&mut self, chunk: MetaStore) {
+        self.write(|txn| self::put(txn, &chunk));
+    }
+
+    fn extend(&mut self, chunks: Vec<MetaStore>) {
+        self.write(|txn| chunks.iter().try_for_each(|x| self::put(txn, x)));
+    }
+
+    fn remove_file(&mut self, file_id: &str) -> Vec<MetaStore> {
+        self.write(|txn| {
+            let removed: Vec<MetaStore> = txn
+                .open_table(CHUNKS)?
+                .extract_from_if((file_id, i32::MIN)..=(file_id, i32::MAX), |_, _| true)?
+                .filter_map(|x| x.ok().and_then(|y| self::decode(y.1.value())))
+                .collect();
+            for chunk in removed.iter() {
+                self::unindex(txn, chunk)?;
+            }
+            Ok(removed)
+        })
+        .unwrap_or_default()
+    }
+
+    fn clear(&mut self) {
+        self.write(|txn| {
+            txn.delete_table(CHUNKS)?;
+            txn.delete_multimap_table(FILES)?;
+            txn.delete_multimap_table(NAMES)?;
+            txn.delete_multimap_table(HOSTS)?;
+            txn.open_table(CHUNKS)?;
+            txn.open_multimap_table(FILES)?;
+            txn.open_multimap_table(NAMES)?;
+            txn.open_multimap_table(HOSTS)?;
+            Ok(())
+        });
+    }
+
+    fn names(&self) -> Vec<String> {
+        self.read(|txn| {
+            let mut names = vec![];
+            for entry in txn.open_multimap_table(FILES)?.iter()? {
+                names.push(entry?.0.value().to_string());
+            }
+            Ok(names)
+        })
+    }
+
+    fn file_ids(&self, name: &str) -> Vec<String> {
+        self.read(|txn| {
+            let mut ids = vec![];
+            for id in txn.open_multimap_table(FILES)?.get(name)? {
+                ids.push(id?.value().to_string());
+            }
+            Ok(ids)
+        })
+    }
+
+    fn file(&self, file_id: &str) -> Vec<MetaStore> {
+        self.read(|txn| self::chunks_of(txn, file_id))
+    }
+
+    fn references(&self, name: &str) -> usize {
+        self.read(|txn| Ok(txn.open_multimap_table(NAMES)?.get(name)?.len() as usize))
+    }
+
+    fn stored(&self, name: &str) -> Option<MetaStore> {
+        self.read(|txn| {
+            let Some(key) = txn.open_multimap_table(NAMES)?.get(name)?.next() else {
+                return Ok(None);
+            };
+            let key = key?;
+            let (file_id, chunk_id) = key.value();
+            let chunk = txn.open_table(CHUNKS)?.get((file_id, chunk_id))?;
+            Ok(chunk.and_then(|x| self::decode(x.value())))
+        })
+    }
+
+    fn placed(&self, ip: &str, name: &str) -> Vec<Key> {
+        self.read(|txn| {
+            let mut keys = vec![];
+            for key in txn.open_multimap_table(HOSTS)?.get((ip, name))? {
+                let key = key?;
+                let (file_id, chunk_id) = key.value();
+                keys.push((file_id.to_string(), chunk_id));
+            }
+            Ok(keys)
+        })
+    }
+
+    fn durable(&self) -> bool {
+        true
+    }
+}
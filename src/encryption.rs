@@ -0,0 +1,68 @@
+use crate::checksum;
+use base64::prelude::*;
+use chacha20poly1305::aead::{Aead, KeyInit};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use std::fs;
+
+pub const KEY_SIZE: usize = 32;
+pub const NONCE_SIZE: usize = 12;
+/// every encrypted chunk is this many bytes larger than its plaintext
+pub const TAG_SIZE: u64 = 16;
+
+/// loads a base64 encoded 32 byte key e.g created via `head -c 32 /dev/urandom | base64`
+pub fn load_key(path: &str) -> Option<Vec<u8>> {
+    let key = BASE64_STANDARD
+        .decode(fs::read_to_string(path).ok()?.trim())
+        .ok()?;
+    (key.len() == KEY_SIZE).then_some(key)
+}
+
+pub fn generate_key() -> Vec<u8> {
+    rand::random::<[u8; KEY_SIZE]>().to_vec()
+}
+
+pub fn encrypt(key: &[u8], nonce: &[u8; NONCE_SIZE], data: &[u8]) -> Option<Vec<u8>> {
+    ChaCha20Poly1305::new(Key::from_slice(key))
+        .encrypt(Nonce::from_slice(nonce), data)
+        .ok()
+}
+
+pub fn decrypt(key: &[u8], nonce: &[u8; NONCE_SIZE], data: &[u8]) -> Option<Vec<u8>> {
+    ChaCha20Poly1305::new(Key::from_slice(key))
+        .decrypt(Nonce::from_slice(nonce), data)
+        .ok()
+}
+
+/// every file has its own data key, so the chunk id alone is a unique nonce for that key
+pub fn chunk_nonce(chunk_id: i32) -> [u8; NONCE_SIZE] {
+    let mut nonce = [0; NONCE_SIZE];
+    nonce[NONCE_SIZE - 4..].copy_from_slice(&chunk_id.to_be_bytes());
+    nonce
+}
+
+/// encrypts with a random nonce which is prepended to the result, for keys that encrypt many things
+pub fn seal(key: &[u8], data: &[u8]) -> Option<Vec<u8>> {
+    let nonce = rand::random::<[u8; NONCE_SIZE]>();
+    let mut sealed = nonce.to_vec();
+    sealed.extend(self::encrypt(key, &nonce, data)?);
+    Some(sealed)
+}
+
+pub fn open(key: &[u8], sealed: &[u8]) -> Option<Vec<u8>> {
+    let (nonce, data) = sealed.split_at_checked(NONCE_SIZE)?;
+    self::decrypt(key, nonce.try_into().ok()?, data)
+}
+
+/// a short fingerprint of a key so we can tell which key something was encrypted with
+pub fn key_id(key: &[u8]) -> String {
+    checksum::hash(key)[..16].to_string()
+}
+
+/// encrypts a data key with the key encryption key
+pub fn wrap(kek: &[u8], key: &[u8]) -> Option<String> {
+    Some(BASE64_STANDARD.encode(self::seal(kek, key)?))
+}
+
+pub fn unwrap(kek: &[u8], wrapped: &str) -> Option<Vec<u8>> {
+    self::open(kek, &BASE64_STANDARD.decode(wrapped).ok()?)
+}
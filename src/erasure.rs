@@ -0,0 +1,47 @@
+use reed_solomon_erasure::galois_8::ReedSolomon;
+
+/// splits a chunk into `data` equally sized shards (zero padded) followed by `parity` shards
+pub fn encode(chunk: &[u8], data: usize, parity: usize) -> Option<Vec<Vec<u8>>> {
+    let codec = ReedSolomon::new(data, parity).ok()?;
+    let size = chunk.len().div_ceil(data).max(1);
+
+    let mut shards: Vec<Vec<u8>> = (0..data + parity)
+        .map(|i| {
+            let mut shard = chunk
+                .get(i * size..chunk.len().min((i + 1) * size))
+                .unwrap_or_default()
+                .to_vec();
+            shard.resize(size, 0);
+            shard
+        })
+        .collect();
+
+    codec.encode(&mut shards).ok()?;
+    Some(shards)
+}
+
+/// rebuilds every missing shard, at least `data` of the shards have to be present
+pub fn reconstruct(
+    mut shards: Vec<Option<Vec<u8>>>,
+    data: usize,
+    parity: usize,
+) -> Option<Vec<Vec<u8>>> {
+    let codec = ReedSolomon::new(data, parity).ok()?;
+    codec.reconstruct(&mut shards).ok()?;
+    shards.into_iter().collect()
+}
+
+/// joins the data shards back together and strips the padding
+pub fn decode(shards: &[Vec<u8>], data: usize, size: usize) -> Vec<u8> {
+    let mut chunk: Vec<u8> = shards.iter().take(data).flatten().copied().collect();
+    chunk.truncate(size);
+    chunk
+}
+
+/// parses a policy in the form of `<data>+<parity>` e.g `6+3`
+pub fn parse(policy: &str) -> Option<(usize, usize)> {
+    let (data, parity) = policy.split_once('+')?;
+    let (data, parity) = (data.trim().parse().ok()?, parity.trim().parse().ok()?);
+    ReedSolomon::new(data, parity).ok()?;
+    Some((data, parity))
+}
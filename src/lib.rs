@@ -0,0 +1,34 @@
+#[macro_use]
+extern crate lazy_static;
+
+pub mod acl;
+pub mod audit;
+pub mod auth;
+pub mod catalog;
+pub mod checksum;
+pub mod client;
+pub mod compression;
+pub mod config;
+pub mod database;
+pub mod encryption;
+pub mod erasure;
+pub mod limit;
+pub mod master;
+pub mod quota;
+pub mod raft;
+pub mod recovery;
+pub mod shadow;
+pub mod snapshot;
+pub mod tls;
+pub mod worker;
+
+pub const LOGO: &str = r#"
+
+██████  ██████  ███████ ███████
+██   ██ ██   ██ ██      ██
+██████  ██   ██ █████   ███████
+██   ██ ██   ██ ██           ██
+██   ██ ██████  ██      ███████
+
+ a toy distributed file system
+"#;
@@ -0,0 +1,128 @@
+use axum::{
+    body::HttpBody,
+    extract::{Request, State},
+    http::{header, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Instant;
+use tokio::sync::Semaphore;
+use tracing::warn;
+
+use crate::auth::{Identity, Scope};
+use crate::config;
+use crate::config::Config;
+
+/// what a token has left to spend, both buckets hold at most a second's worth
+struct Bucket {
+    requests: f64,
+    bytes: f64,
+    updated: Instant,
+}
+
+lazy_static! {
+    static ref BUCKETS: Mutex<HashMap<String, Bucket>> = Mutex::new(HashMap::new());
+    /// one permit per request the node may handle at once
+    static ref INFLIGHT: Option<Semaphore> = config::get()
+        .and_then(|x| x.max_concurrent)
+        .map(Semaphore::new);
+}
+
+fn too_many_requests(seconds: u64) -> Response {
+    (
+        StatusCode::TOO_MANY_REQUESTS,
+        [(header::RETRY_AFTER, seconds.max(1).to_string())],
+    )
+        .into_response()
+}
+
+/// caps the requests being handled at once, whoever makes them
+pub async fn concurrency(request: Request, next: Next) -> Response {
+    let Some(inflight) = INFLIGHT.as_ref() else {
+        return next.run(request).await;
+    };
+
+    match inflight.try_acquire() {
+        Ok(_permit) => next.run(request).await,
+        Err(_) => {
+            warn!("too many concurrent requests, rejecting {}", request.uri());
+            self::too_many_requests(1)
+        }
+    }
+}
+
+/* -------------------------------------------------------------------------------------------------
+every token gets a bucket of requests and one of bytes, refilled at the configured rate per second.
+A request needs a whole request in the bucket and the byte bucket not to be in debt, its body and
+its response are taken out of the byte bucket afterwards (a single chunk may well be more than a
+second's worth). The nodes themselves (every token with the worker scope) are never limited since
+throttling heartbeats or repairs would only make things worse.
+------------------------------------------------------------------------------------------------- */
+pub async fn throttle(State(config): State<Config>, request: Request, next: Next) -> Response {
+    if config.request_rate.is_none() && config.byte_rate.is_none() {
+        return next.run(request).await;
+    }
+    let Some(identity) = request.extensions().get::<Identity>().cloned() else {
+        return next.run(request).await;
+    };
+    if identity.allows(Scope::Worker) {
+        return next.run(request).await;
+    }
+
+    let received = request.body().size_hint().exact().unwrap_or(0);
+    if let Err(seconds) = self::take(&config, &identity.name, received) {
+        warn!(
+            "token [{}] is over its rate limit, retry in {}s",
+            identity.name, seconds
+        );
+        return self::too_many_requests(seconds);
+    }
+
+    let response = next.run(request).await;
+    let sent = response.body().size_hint().exact().unwrap_or(0);
+    self::charge(&identity.name, sent);
+    response
+}
+
+/// takes a request and its body from the buckets of the token, or says how long to wait
+fn take(config: &Config, name: &str, bytes: u64) -> Result<(), u64> {
+    let Ok(mut buckets) = BUCKETS.lock() else {
+        return Ok(());
+    };
+    let request_rate = config.request_rate.map(|x| x as f64);
+    let byte_rate = config.byte_rate.map(|x| x as f64);
+
+    let now = Instant::now();
+    let bucket = buckets.entry(name.to_string()).or_insert(Bucket {
+        requests: request_rate.unwrap_or_default(),
+        bytes: byte_rate.unwrap_or_default(),
+        updated: now,
+    });
+    let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+    bucket.updated = now;
+
+    let mut wait: f64 = 0.0;
+    if let Some(rate) = request_rate {
+        bucket.requests = (bucket.requests + elapsed * rate).min(rate);
+        wait = wait.max((1.0 - bucket.requests) / rate);
+    }
+    if let Some(rate) = byte_rate {
+        bucket.bytes = (bucket.bytes + elapsed * rate).min(rate);
+        wait = wait.max(-bucket.bytes / rate);
+    }
+    if wait > 0.0 {
+        return Err(wait.ceil() as u64);
+    }
+
+    bucket.requests -= 1.0;
+    bucket.bytes -= bytes as f64;
+    Ok(())
+}
+
+fn charge(name: &str, bytes: u64) {
+    if let Some(bucket) = BUCKETS.lock().ok().as_mut().and_then(|x| x.get_mut(name)) {
+        bucket.bytes -= bytes as f64;
+    }
+}
@@ -1,24 +1,6 @@
 use clap::{Parser, Subcommand};
+use rdfs::{client, master, recovery, shadow, worker, LOGO};
 use tracing::warn;
-#[macro_use]
-extern crate lazy_static;
-
-mod auth;
-mod client;
-mod config;
-mod master;
-mod worker;
-
-const LOGO: &'static str = r#"
-
-██████  ██████  ███████ ███████
-██   ██ ██   ██ ██      ██
-██████  ██   ██ █████   ███████
-██   ██ ██   ██ ██           ██
-██   ██ ██████  ██      ███████
-
- a toy distributed file system
-"#;
 
 #[derive(Parser, Default, Debug)]
 #[clap(
@@ -48,18 +30,122 @@ enum Commands {
     /// Get a remote file e.g rdfs get foo.txt
     Get { file: String },
     /// Add a remote file e.g rdfs add foo.txt
-    Add { file: String },
+    Add {
+        file: String,
+        /// erasure code the file into data + parity shards instead of replicating it e.g 6+3
+        #[arg(long)]
+        erasure: Option<String>,
+        /// compress the chunks on the worker nodes, allowed values are "zstd" or "lz4"
+        #[arg(long)]
+        compress: Option<String>,
+        /// encrypt the file with a data key wrapped by the key in 'RDFS_KEYFILE'
+        #[arg(long)]
+        encrypt: bool,
+    },
     /// Remove a remote file e.g rdfs remove foo.txt
     Remove { file: String },
-    /// Mode: run the binary in either as a "Master" or "Worker" node
+    /// Share a remote file with a link that expires e.g rdfs share foo.txt --expires 12h
+    Share {
+        file: String,
+        /// how long the link stays valid e.g 30m, 12h or 7d (at most a week)
+        #[arg(long, default_value = "1h")]
+        expires: String,
+    },
+    /// Grant a permission on a file or directory e.g rdfs chmod reports/ group:finance read
+    Chmod {
+        path: String,
+        /// either user:<token name> or group:<group name>
+        principal: String,
+        /// allowed values are "read", "write", "admin" or "none" to remove the grant
+        permission: String,
+    },
+    /// Change the owner of a file or directory e.g rdfs chown reports/ alice
+    Chown { path: String, owner: String },
+    /// Show who changed what e.g rdfs audit --user ci --path reports/ --since 24h
+    Audit {
+        /// only calls made with this token
+        #[arg(long)]
+        user: Option<String>,
+        /// only calls on this file or anything below this directory
+        #[arg(long)]
+        path: Option<String>,
+        /// either a time e.g 2024-10-01T00:00:00Z or how long ago e.g 24h
+        #[arg(long)]
+        since: Option<String>,
+        /// either a time e.g 2024-10-02T00:00:00Z or how long ago e.g 1h
+        #[arg(long)]
+        until: Option<String>,
+    },
+    /// Show the storage used and the quotas e.g rdfs quota or rdfs quota set dir:reports --hard 10G
+    Quota {
+        /// only this subject, either user:<owner> or dir:<path>
+        subject: Option<String>,
+        #[command(subcommand)]
+        cmd: Option<QuotaCommands>,
+    },
+    /// Manage the named access tokens e.g rdfs token create ci --scope read
+    Token {
+        #[command(subcommand)]
+        cmd: TokenCommands,
+    },
+    /// Take point-in-time copies of a directory e.g rdfs snapshot create reports/ nightly
+    Snapshot {
+        #[command(subcommand)]
+        cmd: SnapshotCommands,
+    },
+    /// Mode: run the binary in either as a "Master", "Recover", "Shadow" or "Worker" node
     Mode {
-        /// kind: allowed values are "master" or "worker"
+        /// kind: allowed values are "master", "recover", "shadow" or "worker"
         kind: String,
         /// port: a custom port. default is 8888
         port: Option<i16>,
     },
 }
 
+#[derive(Subcommand, Debug, Clone)]
+enum TokenCommands {
+    /// Create a token, it is only shown once e.g rdfs token create ci --scope read
+    Create {
+        name: String,
+        /// allowed values are "read", "write", "admin" or "worker"
+        #[arg(long = "scope", required = true)]
+        scopes: Vec<String>,
+        /// groups the token belongs to, used by access control lists
+        #[arg(long = "group")]
+        groups: Vec<String>,
+    },
+    /// Revoke a token e.g rdfs token revoke ci
+    Revoke { name: String },
+    /// List all tokens and their scopes
+    List,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+enum QuotaCommands {
+    /// Limit the physical bytes of user:<owner> or dir:<path>, no limits removes the quota
+    Set {
+        subject: String,
+        /// uploads past it still succeed with a warning e.g 8G
+        #[arg(long)]
+        soft: Option<String>,
+        /// uploads past it are refused e.g 10G
+        #[arg(long)]
+        hard: Option<String>,
+    },
+}
+
+#[derive(Subcommand, Debug, Clone)]
+enum SnapshotCommands {
+    /// Take a snapshot of a directory, "/" for everything e.g rdfs snapshot create reports/ nightly
+    Create { path: String, name: String },
+    /// List all snapshots
+    List,
+    /// Put the directory back the way it was, files added since are removed
+    Restore { name: String },
+    /// Delete a snapshot e.g rdfs snapshot delete nightly
+    Delete { name: String },
+}
+
 #[tokio::main]
 async fn main() {
     tracing_subscriber::fmt::init();
@@ -70,12 +156,53 @@ async fn main() {
             client::list(path);
         }
         Some(Commands::Get { file }) => {
-            client::get(&file);
+            client::get(file);
         }
-        Some(Commands::Add { file }) => {
-            client::add(&file);
+        Some(Commands::Add {
+            file,
+            erasure,
+            compress,
+            encrypt,
+        }) => {
+            client::add(file, erasure, compress, *encrypt);
         }
-        Some(Commands::Remove { file }) => client::remove(&file),
+        Some(Commands::Remove { file }) => client::remove(file),
+        Some(Commands::Share { file, expires }) => client::share(file, expires),
+        Some(Commands::Chmod {
+            path,
+            principal,
+            permission,
+        }) => client::chmod(path, principal, permission),
+        Some(Commands::Chown { path, owner }) => client::chown(path, owner),
+        Some(Commands::Audit {
+            user,
+            path,
+            since,
+            until,
+        }) => client::audit(user, path, since, until),
+        Some(Commands::Quota { subject, cmd }) => match cmd {
+            Some(QuotaCommands::Set {
+                subject,
+                soft,
+                hard,
+            }) => client::set_quota(subject, soft, hard),
+            None => client::quota(subject),
+        },
+        Some(Commands::Token { cmd }) => match cmd {
+            TokenCommands::Create {
+                name,
+                scopes,
+                groups,
+            } => client::create_token(name, scopes, groups),
+            TokenCommands::Revoke { name } => client::revoke_token(name),
+            TokenCommands::List => client::list_tokens(),
+        },
+        Some(Commands::Snapshot { cmd }) => match cmd {
+            SnapshotCommands::Create { path, name } => client::create_snapshot(path, name),
+            SnapshotCommands::List => client::list_snapshots(),
+            SnapshotCommands::Restore { name } => client::restore_snapshot(name),
+            SnapshotCommands::Delete { name } => client::delete_snapshot(name),
+        },
         Some(Commands::Mode { kind, port }) => match kind.as_ref() {
             "master" => {
                 let default_port = match port {
@@ -84,6 +211,20 @@ async fn main() {
                 };
                 let _ = master::init(default_port).await;
             }
+            "recover" => {
+                let default_port = match port {
+                    Some(p) => p,
+                    None => &8888,
+                };
+                let _ = recovery::init(default_port).await;
+            }
+            "shadow" => {
+                let default_port = match port {
+                    Some(p) => p,
+                    None => &8888,
+                };
+                let _ = shadow::init(default_port).await;
+            }
             "worker" => {
                 let default_port = match port {
                     Some(p) => p,
@@ -92,7 +233,7 @@ async fn main() {
                 let _ = worker::init(default_port).await;
             }
             _ => {
-                warn!("illegal mode, please select option master or worker!");
+                warn!("illegal mode, please select option master, recover, shadow or worker!");
             }
         },
         None => {
@@ -1,14 +1,34 @@
+use crate::acl;
+use crate::acl::Permission;
+use crate::audit;
 use crate::auth;
+use crate::auth::{Capability, Identity, Operation};
+use crate::catalog::{Backend, Catalog};
+use crate::checksum;
+use crate::compression::Codec;
 use crate::config;
 use crate::config::Config;
-use crate::worker::MetaChunk;
+use crate::database::Database;
+use crate::encryption;
+use crate::erasure;
+use crate::limit;
+use crate::quota;
+use crate::raft;
+use crate::raft::Op;
+use crate::recovery;
+use crate::snapshot;
+use crate::tls;
+use crate::tls::NodeId;
+use crate::worker;
+use crate::worker::{ChunkReport, ChunkStatus, DeleteChunk, MetaChunk, TagChunk};
 use axum::extract;
-use axum::extract::{ConnectInfo, State};
-use axum::http::StatusCode;
+use axum::extract::{DefaultBodyLimit, Query, State};
+use axum::http::{header, StatusCode};
 use axum::middleware;
 use axum::response::{IntoResponse, Json, Response};
+use axum::routing;
 use axum::routing::post;
-use axum::Router;
+use axum::{Extension, Router};
 use chrono::{DateTime, Utc};
 use rand::seq::SliceRandom;
 use serde::{Deserialize, Serialize};
@@ -16,47 +36,137 @@ use serde_json::json;
 use std::collections::{HashMap, HashSet};
 use std::fs::{File, OpenOptions};
 use std::io::{BufRead, BufReader, Write};
-use std::net::SocketAddr;
 use std::path::Path;
-use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, RwLock};
 use tracing::{error, info, warn};
 
-const FILE_CHUNK_SIZE: u64 = 512;
+pub const FILE_CHUNK_SIZE: u64 = 512 * 1024;
 const TIMEOUT_IN_MINUTES: i64 = 5;
 const REPLICATION_FACTOR: usize = 3;
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
-struct MetaStore {
-    file_name: String,
-    hash: String,
-    chunk_id: i32,
-    hosts: Vec<Host>,
+pub struct MetaStore {
+    /// unique per upload, two files with the same content still get their own identity
+    #[serde(default)]
+    pub file_id: String,
+    pub file_name: String,
+    /// SHA-256 of the whole file
+    pub hash: String,
+    pub chunk_id: i32,
+    /// SHA-256 of this chunk, empty for chunks uploaded before chunk hashes were recorded
+    #[serde(default)]
+    pub chunk_hash: String,
+    /// length of this chunk in bytes
+    #[serde(default)]
+    pub size: u64,
+    #[serde(default)]
+    pub policy: Policy,
+    /// how the chunk (or its shards) is compressed on the workers
+    #[serde(default)]
+    pub codec: Codec,
+    /// SHA-256 of every erasure coded shard, the shard at index i is stored on host i
+    #[serde(default)]
+    pub shards: Vec<String>,
+    /// the file's data key wrapped by the client's key, empty when the file is not encrypted
+    #[serde(default)]
+    pub wrapped_key: String,
+    /// bumped whenever the master changes the chunk, the workers hold on to the newest they get
+    #[serde(default)]
+    pub version: u64,
+    pub hosts: Vec<Host>,
+}
+
+/// how the chunks of a file are protected against losing worker nodes
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, Default, PartialEq)]
+pub enum Policy {
+    /// every chunk is stored in full on `REPLICATION_FACTOR` worker nodes
+    #[default]
+    Replicated,
+    /// every chunk is split into `data` shards plus `parity` shards, each on its own worker
+    /// node, any `data` shards are enough to read the chunk back
+    Erasure { data: usize, parity: usize },
+}
+
+impl MetaStore {
+    /// the name the chunk is stored under on the worker nodes
+    /// chunks are content addressed, only chunks from before chunk hashes were recorded still
+    /// use their position within the file
+    pub fn chunk_name(&self) -> String {
+        if self.chunk_hash.is_empty() {
+            return format!("{}-{}", self.chunk_id, self.file_id);
+        }
+        self.chunk_hash.to_string()
+    }
+
+    /// the bytes the chunk takes up across the workers, before compression
+    pub fn stored_size(&self) -> u64 {
+        match self.policy {
+            Policy::Replicated => self.size * self.hosts.len() as u64,
+            Policy::Erasure { data, parity } => {
+                self.size.div_ceil(data as u64).max(1) * (data + parity) as u64
+            }
+        }
+    }
+
+    /// every (name, host) pair the chunk (or one of its shards) is stored as on the workers
+    pub fn placements(&self) -> Vec<(String, Host)> {
+        match self.policy {
+            Policy::Replicated => self
+                .hosts
+                .iter()
+                .map(|x| (self.chunk_name(), x.clone()))
+                .collect(),
+            Policy::Erasure { .. } => self
+                .shards
+                .iter()
+                .cloned()
+                .zip(self.hosts.iter().cloned())
+                .collect(),
+        }
+    }
+}
+
+/// appended to the prune log when a file is removed, replaying the snapshot drops every chunk
+/// belonging to that file (and only that file)
+#[derive(Deserialize, Serialize, Debug, Clone)]
+struct Tombstone {
+    file_id: String,
 }
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
-enum Status {
+pub enum Status {
     Unknown,
     Healthy,
     Dead,
 }
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
-struct Host {
-    ip: String,
-    status: Status,
+pub struct Host {
+    pub ip: String,
+    pub status: Status,
 }
 
 lazy_static! {
-    static ref METASTATE: Mutex<Vec<MetaStore>> = Mutex::new(vec![]);
+    static ref METASTATE: RwLock<Box<dyn Backend>> = RwLock::new(Box::new(Catalog::default()));
     static ref HEARTBEAT: Mutex<HashMap<String, DateTime<Utc>>> = Mutex::new(HashMap::new());
 }
 
+/// false once the chunks are kept in the metadata database, the snapshot is then left alone
+static JOURNALED: AtomicBool = AtomicBool::new(true);
+
 pub async fn init(port: &i16) {
     println!("{}", crate::LOGO);
 
     if let Some(config) = config::get() {
-        self::load_snapshot();
-        let _ = self::export_compacted_snapshot();
+        if let Err(e) = self::load_metadata(&config) {
+            error!("Error: {}", e);
+            return;
+        }
+        if let Err(e) = raft::start(&config) {
+            error!("Error: {}", e);
+            return;
+        }
 
         info!("launching node in [master] mode on port {}...", port);
 
@@ -66,68 +176,207 @@ pub async fn init(port: &i16) {
             .route("/get", post(get))
             .route("/upload", post(upload))
             .route("/remove", post(remove))
-            .route_layer(middleware::from_fn(auth::authorise))
+            .route("/report-chunk", post(report_chunk))
+            .route("/create-token", post(auth::create_token))
+            .route("/revoke-token", post(auth::revoke_token))
+            .route("/list-tokens", post(auth::list_tokens))
+            .route("/introspect", post(auth::introspect))
+            .route("/chmod", post(acl::chmod))
+            .route("/chown", post(acl::chown))
+            .route("/quota", post(quota::usage))
+            .route("/set-quota", post(quota::set_quota))
+            .route("/audit", post(audit::query))
+            .route("/create-snapshot", post(snapshot::create_snapshot))
+            .route("/list-snapshots", post(snapshot::list_snapshots))
+            .route("/restore-snapshot", post(snapshot::restore_snapshot))
+            .route("/delete-snapshot", post(snapshot::delete_snapshot))
+            .route("/oplog", post(raft::feed))
+            .route("/raft/vote", post(raft::vote))
+            .route("/raft/append", post(raft::append))
+            .route(
+                "/raft/install",
+                post(raft::install).layer(DefaultBodyLimit::max(raft::MAX_INSTALL_BODY)),
+            )
+            .route_layer(middleware::from_fn(audit::record))
+            .route_layer(middleware::from_fn(raft::forward))
+            .route_layer(middleware::from_fn(recovery::guard))
+            .route_layer(middleware::from_fn_with_state(
+                config.clone(),
+                limit::throttle,
+            ))
+            .route_layer(middleware::from_fn_with_state(
+                config.clone(),
+                auth::authorise,
+            ))
+            // share links carry their own signature instead of a token
+            .route("/share", routing::get(share))
+            .layer(middleware::from_fn(limit::concurrency))
             .with_state(config.clone());
 
-        let listener = tokio::net::TcpListener::bind(format!("0.0.0.0:{}", port))
-            .await
-            .unwrap();
-
-        axum::serve(
-            listener,
-            app.into_make_service_with_connect_info::<SocketAddr>(),
-        )
-        .await
-        .unwrap()
+        tls::serve(app, port, &config).await.unwrap()
     } else {
         error!("Error: unable able to load the valid cluster configuration. Please make sure the ENV 'RDFS_ENDPOINT' and 'RDFS_TOKEN' are set");
     }
 }
 
-async fn heartbeat(ConnectInfo(addr): ConnectInfo<SocketAddr>) -> String {
-    info!("got a heartbeat from worker node -> ...{}", addr);
+/// loads the snapshot (or opens the metadata database) and the token, ACL and quota stores from
+/// the current directory
+pub fn load_metadata(config: &Config) -> Result<(), String> {
+    if let Some(path) = &config.metadata_db {
+        let database = Database::open(path)?;
+        info!("keeping the metadata of the chunks in [{}]", path);
+        if let Ok(mut memory) = METASTATE.write() {
+            *memory = Box::new(database);
+        }
+        JOURNALED.store(false, Ordering::SeqCst);
+    }
+    self::load_snapshot();
+    let _ = self::export_compacted_snapshot();
+    auth::load_tokens();
+    acl::load_acls();
+    quota::load_quotas();
+    snapshot::load_snapshots();
+    Ok(())
+}
+
+/// workers are tracked by their node id, with mutual TLS it has to be an address the cluster can
+/// reach them on since it ends up as the host of their chunks
+async fn heartbeat(NodeId(node): NodeId) -> String {
+    info!("got a heartbeat from worker node -> ...{}", node);
 
     if let Ok(mut heartbeat) = HEARTBEAT.lock() {
         let ts = chrono::Utc::now();
-        heartbeat
-            .entry(addr.ip().to_string())
-            .and_modify(|x| *x = ts)
-            .or_insert(ts);
+        heartbeat.entry(node).and_modify(|x| *x = ts).or_insert(ts);
     }
 
     "ok".to_string()
 }
 
+/// the query of a pre-signed download link
 #[derive(Deserialize, Serialize)]
-struct FileMeta {
-    name: String,
+pub struct ShareLink {
+    pub file: String,
+    pub expires: i64,
+    pub key: String,
+    pub signature: String,
+}
+
+/// streams a whole file to anyone holding a valid share link, the master fetches the chunks from
+/// healthy replicas itself so the link works without a token or access to the workers
+async fn share(State(state): State<Config>, Query(link): Query<ShareLink>) -> Response {
+    let Some(identity) =
+        auth::verify_share(&state, &link.file, link.expires, &link.key, &link.signature).await
+    else {
+        return StatusCode::FORBIDDEN.into_response();
+    };
+    if !acl::permitted(&identity, &link.file, Permission::Read) {
+        return StatusCode::FORBIDDEN.into_response();
+    }
+    info!(
+        "serving file [{}] shared by [{}]",
+        &link.file, identity.name
+    );
+
+    let mut chunks: Vec<MetaStore> = vec![];
+    if let Ok(memory) = METASTATE.read() {
+        if let Some(file_id) = memory.file_ids(&link.file).first() {
+            chunks = memory.file(file_id);
+        }
+    }
+
+    let Some(first) = chunks.first() else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    if !first.wrapped_key.is_empty() {
+        // only the client holding the keyfile can decrypt the file
+        return StatusCode::UNPROCESSABLE_ENTITY.into_response();
+    }
+
+    let (hash, config) = (first.hash.to_string(), state.clone());
+    let data = tokio::task::spawn_blocking(move || {
+        let mut data: Vec<u8> = vec![];
+        for chunk in chunks.iter() {
+            data.extend(worker::fetch_chunk(chunk, &config)?);
+        }
+        Some(data).filter(|x| checksum::hash(x) == hash)
+    })
+    .await;
+
+    match data {
+        Ok(Some(data)) => (
+            [
+                (
+                    header::CONTENT_TYPE,
+                    String::from("application/octet-stream"),
+                ),
+                (
+                    header::CONTENT_DISPOSITION,
+                    format!("attachment; filename=\"{}\"", link.file.replace('"', "")),
+                ),
+            ],
+            data,
+        )
+            .into_response(),
+        _ => StatusCode::SERVICE_UNAVAILABLE.into_response(),
+    }
 }
 
 #[derive(Deserialize, Serialize)]
-struct FileUploadMeta {
-    name: String,
-    hash: String,
-    size: u64,
+pub struct FileMeta {
+    pub name: String,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct FileUploadMeta {
+    pub name: String,
+    /// SHA-256 of the whole file
+    pub hash: String,
+    pub size: u64,
+    /// SHA-256 of every chunk in order, the file is split into `FILE_CHUNK_SIZE` chunks
+    pub chunks: Vec<String>,
+    #[serde(default)]
+    pub policy: Policy,
+    #[serde(default)]
+    pub codec: Codec,
+    /// SHA-256 of every shard of every chunk when the policy is erasure coding
+    #[serde(default)]
+    pub shards: Vec<Vec<String>>,
+    /// set when the client encrypted the chunks, the hashes of the chunks and shards are then
+    /// over the encrypted bytes while the hash of the file is over the plaintext
+    #[serde(default)]
+    pub wrapped_key: String,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct UploadPlan {
+    /// chunks the client still has to send to each of their hosts
+    pub pending: Vec<MetaStore>,
+    /// chunks whose content is already stored in the cluster
+    pub existing: Vec<MetaStore>,
+    /// what the workers need to accept the pending chunks, by chunk (or shard) name
+    #[serde(default)]
+    pub capabilities: HashMap<String, Capability>,
+    /// soft quotas the upload went over
+    #[serde(default)]
+    pub warnings: Vec<String>,
 }
 
 #[axum::debug_handler]
-async fn list() -> Response {
+pub async fn list(Extension(identity): Extension<Identity>) -> Response {
     info!("list all files");
 
     let mut files: Vec<String> = vec![];
 
-    if let Ok(memory) = METASTATE.lock() {
+    if let Ok(memory) = METASTATE.read() {
         files = memory
-            .clone()
-            .into_iter()
-            .map(|x| x.file_name)
-            .collect::<HashSet<_>>()
+            .names()
             .into_iter()
-            .collect::<Vec<_>>()
-            .to_vec();
+            .filter(|x| !snapshot::hidden(x))
+            .filter(|x| acl::permitted(&identity, x, Permission::Read))
+            .collect();
     }
 
-    if files.len() > 0 {
+    if !files.is_empty() {
         return Json(files).into_response();
     }
 
@@ -135,20 +384,23 @@ async fn list() -> Response {
 }
 
 #[axum::debug_handler]
-async fn get(extract::Json(payload): extract::Json<FileMeta>) -> Response {
+pub async fn get(
+    Extension(identity): Extension<Identity>,
+    extract::Json(payload): extract::Json<FileMeta>,
+) -> Response {
     info!("get file with name [{}]", &payload.name);
 
+    if !acl::permitted(&identity, &payload.name, Permission::Read) {
+        return StatusCode::FORBIDDEN.into_response();
+    }
+
     let mut file: Vec<MetaStore> = vec![];
 
-    if let Ok(memory) = METASTATE.lock() {
-        file = memory
-            .clone()
-            .into_iter()
-            .filter(|x| x.file_name == payload.name)
-            .collect::<Vec<MetaStore>>();
+    if let Ok(memory) = METASTATE.read() {
+        file = memory.named(&payload.name);
     }
 
-    if file.len() > 0 {
+    if !file.is_empty() {
         return Json(file).into_response();
     }
 
@@ -156,9 +408,25 @@ async fn get(extract::Json(payload): extract::Json<FileMeta>) -> Response {
 }
 
 #[axum::debug_handler]
-async fn upload(extract::Json(payload): extract::Json<FileUploadMeta>) -> Response {
+async fn upload(
+    State(state): State<Config>,
+    Extension(identity): Extension<Identity>,
+    extract::Json(payload): extract::Json<FileUploadMeta>,
+) -> Response {
     info!("upload file with name [{}]", &payload.name);
 
+    // new files need write access to their directory, uploading again needs it on the file itself
+    if !acl::permitted(&identity, &payload.name, Permission::Write) {
+        return StatusCode::FORBIDDEN.into_response();
+    }
+    if snapshot::hidden(&payload.name) {
+        return (
+            StatusCode::BAD_REQUEST,
+            "the files of a snapshot can't be changed",
+        )
+            .into_response();
+    }
+
     /* ---------------------------------------------------------------------------------------------
     **CAUTION:**
 
@@ -202,68 +470,174 @@ async fn upload(extract::Json(payload): extract::Json<FileUploadMeta>) -> Respon
     this could happen slowly when idle as it's not super important.
     --------------------------------------------------------------------------------------------- */
 
-    let mut worker_nodes: Vec<String> = Vec::new();
-    let mut heartbeats = HashMap::new();
-    let now = chrono::Utc::now();
-
-    if let Ok(x) = HEARTBEAT.lock() {
-        heartbeats = x.clone();
+    // the hashes are checked by the workers and the client, so at least make sure they are sane
+    let expected_chunks = payload.size.div_ceil(FILE_CHUNK_SIZE).max(1) as usize;
+    let hosts_per_chunk = match payload.policy {
+        Policy::Replicated => REPLICATION_FACTOR,
+        Policy::Erasure { data, parity } => data + parity,
+    };
+    let valid_shards = match payload.policy {
+        Policy::Replicated => payload.shards.is_empty(),
+        Policy::Erasure { data, parity } => {
+            erasure::parse(&format!("{}+{}", data, parity)).is_some()
+                && payload.shards.len() == expected_chunks
+                && payload
+                    .shards
+                    .iter()
+                    .all(|x| x.len() == hosts_per_chunk && x.iter().all(|y| checksum::is_hash(y)))
+        }
+    };
+    if !checksum::is_hash(&payload.hash)
+        || payload.chunks.len() != expected_chunks
+        || !payload.chunks.iter().all(|x| checksum::is_hash(x))
+        || !valid_shards
+    {
+        warn!(
+            "rejecting upload of [{}] with invalid hashes",
+            &payload.name
+        );
+        return StatusCode::BAD_REQUEST.into_response();
     }
 
-    worker_nodes = heartbeats
-        .into_iter()
-        .filter(|v| (now - v.1).num_minutes() <= TIMEOUT_IN_MINUTES)
-        .map(|v| v.0)
-        .collect();
-
-    if worker_nodes.len() >= REPLICATION_FACTOR {
-        let chunks = payload.size / FILE_CHUNK_SIZE;
-        let mut metastore: Vec<MetaStore> = Vec::new();
-
-        // when file is less than 512kb
-        if chunks == 0 {
-            let hosts: Vec<Host> = worker_nodes
-                .choose_multiple(&mut rand::thread_rng(), 3)
-                .map(|x| Host {
-                    ip: x.to_string(),
-                    status: Status::Healthy,
-                })
-                .collect();
+    let worker_nodes = self::workers();
+
+    if worker_nodes.len() >= hosts_per_chunk {
+        let file_id = format!("{:032x}", rand::random::<u128>());
+        let mut tags: Vec<(String, TagChunk)> = vec![];
+        let mut plan = UploadPlan {
+            pending: vec![],
+            existing: vec![],
+            capabilities: HashMap::new(),
+            warnings: vec![],
+        };
+
+        /* -----------------------------------------------------------------------------------------
+        chunks are addressed by their content hash, so any chunk that is already stored in the
+        cluster (or that appears earlier in this very file) keeps the hosts it already lives on
+        and the client can skip sending it. We trust that the earlier upload of that content did
+        actually reach its workers, the scrubber and repair loop take care of lost replicas.
+        ----------------------------------------------------------------------------------------- */
+        if let Ok(mut memory) = METASTATE.write() {
+            let mut known: HashMap<String, MetaStore> = HashMap::new();
+
+            for (chunk, chunk_hash) in (1..).zip(payload.chunks.iter()) {
+                let offset = (chunk as u64 - 1) * FILE_CHUNK_SIZE;
+                let overhead = match payload.wrapped_key.is_empty() {
+                    true => 0,
+                    false => encryption::TAG_SIZE,
+                };
+                let mut meta = MetaStore {
+                    file_id: file_id.to_string(),
+                    file_name: payload.name.to_string(),
+                    hash: payload.hash.to_string(),
+                    chunk_id: chunk,
+                    chunk_hash: chunk_hash.to_string(),
+                    size: (payload.size - offset).min(FILE_CHUNK_SIZE) + overhead,
+                    policy: payload.policy,
+                    codec: payload.codec,
+                    shards: payload
+                        .shards
+                        .get(chunk as usize - 1)
+                        .cloned()
+                        .unwrap_or_default(),
+                    wrapped_key: payload.wrapped_key.to_string(),
+                    version: 1,
+                    hosts: vec![],
+                };
+
+                // existing content keeps the policy it was originally stored with
+                let name = meta.chunk_name();
+                if let Some(existing) = known.get(&name).cloned().or_else(|| memory.stored(&name)) {
+                    meta.policy = existing.policy;
+                    meta.codec = existing.codec;
+                    meta.shards = existing.shards.clone();
+                    meta.hosts = existing.hosts.clone();
+                    plan.existing.push(meta);
+                    continue;
+                }
 
-            metastore.push(MetaStore {
-                file_name: payload.name.to_string(),
-                hash: payload.hash.to_string(),
-                chunk_id: 1,
-                hosts: hosts,
-            });
-        }
+                // randomly pick X worker nodes, shards of a chunk all end up on distinct workers
+                meta.hosts = worker_nodes
+                    .choose_multiple(&mut rand::thread_rng(), hosts_per_chunk)
+                    .map(|x| Host {
+                        ip: x.to_string(),
+                        status: Status::Healthy,
+                    })
+                    .collect();
+                known.insert(meta.chunk_name(), meta.clone());
+                plan.pending.push(meta);
+            }
 
-        for chunk in 1..chunks {
-            // randomly pick X worker nodes
-            let hosts: Vec<Host> = worker_nodes
-                .choose_multiple(&mut rand::thread_rng(), 3)
-                .map(|x| Host {
-                    ip: x.to_string(),
-                    status: Status::Healthy,
-                })
+            // the file is charged to its owner, which is the uploader unless it already has one
+            let owner = acl::owner(&payload.name).unwrap_or(identity.name.to_string());
+            let upload: Vec<MetaStore> = plan
+                .pending
+                .iter()
+                .chain(plan.existing.iter())
+                .cloned()
                 .collect();
+            match quota::check(memory.as_ref(), &owner, &payload.name, &upload) {
+                Ok(warnings) => plan.warnings = warnings,
+                Err(reason) => {
+                    warn!("refusing upload of [{}]: {}", &payload.name, reason);
+                    return (StatusCode::INSUFFICIENT_STORAGE, reason).into_response();
+                }
+            }
 
-            metastore.push(MetaStore {
-                file_name: payload.name.to_string(),
-                hash: payload.hash.to_string(),
-                chunk_id: chunk as i32,
-                hosts: hosts,
-            });
+            for line in plan.pending.iter().chain(plan.existing.iter()) {
+                self::log_chunk(line);
+                memory.insert(line.clone());
+            }
+
+            /* -------------------------------------------------------------------------------------
+            the workers keep the metadata of every file referencing a chunk next to it, so that it
+            can be recovered if the snapshot is ever lost (see `recovery`). New chunks get it with
+            their capability, chunks that are already stored are told about the new file instead.
+            ------------------------------------------------------------------------------------- */
+            for chunk in plan.pending.iter() {
+                let files: Vec<MetaStore> = upload
+                    .iter()
+                    .filter(|x| x.chunk_name() == chunk.chunk_name())
+                    .cloned()
+                    .collect();
+                for (name, _) in chunk.placements() {
+                    let capability =
+                        auth::grant(&state.token, &name, Operation::Store, files.clone());
+                    plan.capabilities.insert(name, capability);
+                }
+            }
+            for chunk in plan.existing.iter() {
+                if plan.capabilities.contains_key(&chunk.chunk_name())
+                    || chunk
+                        .shards
+                        .first()
+                        .is_some_and(|x| plan.capabilities.contains_key(x))
+                {
+                    continue;
+                }
+                for (name, host) in chunk.placements() {
+                    tags.push((host.ip, self::tag(&name, chunk, Some(chunk.clone()))));
+                }
+            }
         }
 
-        for line in metastore.iter() {
-            self::append("snapshot", &format!("{}", json!(line)));
+        if !tags.is_empty() {
+            let config = state.clone();
+            tokio::task::spawn_blocking(move || self::tag_remote_chunks(tags, &config));
         }
 
-        return Json(metastore).into_response();
+        acl::claim(&payload.name, &identity.name);
+        info!(
+            "upload of [{}] needs {} new chunk(s), {} already stored",
+            &payload.name,
+            plan.pending.len(),
+            plan.existing.len()
+        );
+
+        return Json(plan).into_response();
     }
 
-    if worker_nodes.len() < REPLICATION_FACTOR {
+    if worker_nodes.len() < hosts_per_chunk {
         return StatusCode::SERVICE_UNAVAILABLE.into_response();
     }
 
@@ -273,54 +647,476 @@ async fn upload(extract::Json(payload): extract::Json<FileUploadMeta>) -> Respon
 #[axum::debug_handler]
 async fn remove(
     State(state): State<Config>,
+    Extension(identity): Extension<Identity>,
     extract::Json(payload): extract::Json<FileMeta>,
 ) -> Response {
     info!("remove file with name [{}]", &payload.name);
 
-    let mut kill_list: Vec<MetaStore> = vec![];
+    if !acl::permitted(&identity, &payload.name, Permission::Write) {
+        return StatusCode::FORBIDDEN.into_response();
+    }
+    if snapshot::hidden(&payload.name) {
+        return (
+            StatusCode::BAD_REQUEST,
+            "the files of a snapshot go away with the snapshot",
+        )
+            .into_response();
+    }
 
-    if let Ok(memory) = METASTATE.lock() {
-        kill_list = memory
-            .clone()
-            .into_iter()
-            .filter(|x| x.file_name == payload.name)
-            .collect::<Vec<_>>()
-            .to_vec();
+    let mut tree = Tree::default();
+    if let Ok(mut memory) = METASTATE.write() {
+        self::unlink(memory.as_mut(), vec![payload.name.to_string()], &mut tree);
     }
 
-    let mut kill_hash = String::new();
+    if tree.removed.is_empty() {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+
+    self::settle(tree, &state).await;
+    acl::forget(&payload.name);
+
+    Json(FileMeta { name: payload.name }).into_response()
+}
+
+/// the files removed and copied by a change to the metadata, and what is left to tell the workers
+#[derive(Default)]
+pub struct Tree {
+    pub removed: Vec<String>,
+    pub copied: Vec<String>,
+    /// chunks no file references any more
+    kill_list: Vec<MetaStore>,
+    /// chunks that gained or lost a file but are still referenced
+    tags: Vec<(String, TagChunk)>,
+}
+
+/// drops every upload of the names from the metadata
+fn unlink(memory: &mut dyn Backend, names: Vec<String>, tree: &mut Tree) {
+    // the same name may have been uploaded more than once, each upload is its own file
+    let mut removed: Vec<MetaStore> = vec![];
+    for name in names {
+        let file_ids = memory.file_ids(&name);
+        if file_ids.is_empty() {
+            continue;
+        }
+        for file_id in file_ids {
+            removed.extend(memory.remove_file(&file_id));
+            self::log_prune(&file_id);
+        }
+        tree.removed.push(name);
+    }
+
+    // a chunk is only deleted from the workers once no other file references it, otherwise
+    // the workers just forget about the removed file
+    let mut seen: HashSet<String> = HashSet::new();
+    for chunk in removed {
+        if !seen.insert(chunk.chunk_name()) {
+            continue;
+        }
+        if memory.references(&chunk.chunk_name()) == 0 {
+            tree.kill_list.push(chunk);
+            continue;
+        }
+        for (name, host) in chunk.placements() {
+            tree.tags.push((host.ip, self::tag(&name, &chunk, None)));
+        }
+    }
+}
 
-    if kill_list.len() > 0 {
-        for chunk in kill_list {
-            for worker in chunk.hosts {
-                let chunk_id = format!("{}-{}", chunk.chunk_id, chunk.hash);
-                kill_hash = chunk.hash.to_string();
-                let token = state.token.to_string();
-                let _ = tokio::task::spawn_blocking(move || {
-                    self::delete_remote_chunk(chunk_id, worker.ip, &token)
+/* -------------------------------------------------------------------------------------------------
+replaces every file below `to` with a copy of every file below `from` (or with nothing at all), both
+in a single step so no one sees half of the tree. A copy is only new metadata: it gets its own file
+id but references the very same chunks as the original, so nothing is stored twice and the workers
+only need to be told about the new file. This is what snapshots are made of (see `snapshot`).
+------------------------------------------------------------------------------------------------- */
+pub fn replace_tree(from: Option<&str>, to: &str) -> Tree {
+    let mut tree = Tree::default();
+
+    if let Ok(mut memory) = METASTATE.write() {
+        let names = memory.names();
+        let sources: Vec<(String, String)> = match from {
+            Some(from) => names
+                .iter()
+                .filter_map(|x| {
+                    Some((x.to_string(), snapshot::join(to, snapshot::below(x, from)?)))
                 })
-                .await;
+                .collect(),
+            None => vec![],
+        };
+        let targets: Vec<String> = names
+            .into_iter()
+            .filter(|x| snapshot::below(x, to).is_some())
+            .collect();
+        self::unlink(memory.as_mut(), targets, &mut tree);
+
+        for (source, target) in sources {
+            for file_id in memory.file_ids(&source) {
+                let copy_id = format!("{:032x}", rand::random::<u128>());
+                for mut chunk in memory.file(&file_id) {
+                    chunk.file_id = copy_id.to_string();
+                    chunk.file_name = target.to_string();
+                    chunk.version = 1;
+                    for (name, host) in chunk.placements() {
+                        tree.tags
+                            .push((host.ip, self::tag(&name, &chunk, Some(chunk.clone()))));
+                    }
+                    self::log_chunk(&chunk);
+                    memory.insert(chunk);
+                }
             }
+            tree.copied.push(target);
+        }
+    }
+    tree
+}
+
+/// deletes the chunks no file references any more from the workers and tags the others
+pub async fn settle(tree: Tree, config: &Config) {
+    if !tree.tags.is_empty() {
+        let (tags, config) = (tree.tags, config.clone());
+        tokio::task::spawn_blocking(move || self::tag_remote_chunks(tags, &config));
+    }
+
+    for chunk in tree.kill_list {
+        for (chunk_id, worker) in chunk.placements() {
+            let config = config.clone();
+            let _ = tokio::task::spawn_blocking(move || {
+                self::delete_remote_chunk(chunk_id, worker.ip, &config)
+            })
+            .await;
         }
     }
+}
+
+#[axum::debug_handler]
+async fn report_chunk(
+    State(state): State<Config>,
+    NodeId(reporter): NodeId,
+    extract::Json(payload): extract::Json<ChunkReport>,
+) -> Response {
+    if let ChunkStatus::Unexpected = payload.status {
+        // nothing to repair, the file is either an orphaned chunk or not a chunk at all
+        warn!(
+            "worker [{}] reported unexpected file [{}]",
+            reporter, &payload.id
+        );
+        return Json(MetaChunk { id: payload.id }).into_response();
+    }
+
+    warn!(
+        "worker [{}] reported corrupt chunk [{}]",
+        reporter, &payload.id
+    );
+
+    /* ---------------------------------------------------------------------------------------------
+    the replica on the reporting worker is marked as dead straight away so that it is no longer
+    handed out to clients, we then ask any of the remaining healthy replicas to send a good copy
+    back to the reporting worker which overwrites the corrupt chunk (and its checksums). Once that
+    succeeds the replica is flipped back to healthy.
 
-    if let Ok(mut memory) = METASTATE.lock() {
-        memory.retain(|x| x.file_name != payload.name)
+    erasure coded shards have no replicas, instead the master pulls enough of the other shards of
+    the chunk to reconstruct the lost one and stores it back on the reporting worker.
+    --------------------------------------------------------------------------------------------- */
+    let mut repair: Option<Repair> = None;
+    let mut files: Vec<MetaStore> = vec![];
+
+    if let Ok(mut memory) = METASTATE.write() {
+        for key in memory.placed(&reporter, &payload.id) {
+            let Some(mut chunk) = memory.get(&key) else {
+                continue;
+            };
+            let Some(index) = chunk
+                .placements()
+                .iter()
+                .position(|(name, host)| *name == payload.id && host.ip == reporter)
+            else {
+                continue;
+            };
+
+            chunk.hosts[index].status = Status::Dead;
+            chunk.version += 1;
+            self::log_chunk(&chunk);
+            files.push(chunk.clone());
+
+            if repair.is_none() {
+                repair = Some(match chunk.policy {
+                    Policy::Replicated => Repair::Replica(
+                        chunk
+                            .hosts
+                            .iter()
+                            .filter(|x| matches!(x.status, Status::Healthy))
+                            .map(|x| x.ip.to_string())
+                            .collect(),
+                    ),
+                    Policy::Erasure { .. } => Repair::Shard(chunk.clone(), index),
+                });
+            }
+            memory.insert(chunk);
+        }
     }
 
-    self::append("prune", &kill_hash);
+    let config = state.clone();
+    let repaired = match repair {
+        Some(Repair::Replica(sources)) if !sources.is_empty() => {
+            let id = payload.id.to_string();
+            let target = reporter.to_string();
+            tokio::task::spawn_blocking(move || {
+                self::repair_remote_chunk(&id, sources, &target, files, &config)
+            })
+            .await
+            .unwrap_or(false)
+        }
+        Some(Repair::Shard(chunk, index)) => tokio::task::spawn_blocking(move || {
+            self::rebuild_remote_shard(&chunk, index, files, &config)
+        })
+        .await
+        .unwrap_or(false),
+        _ => {
+            error!("no healthy replica left to repair chunk [{}]", &payload.id);
+            return StatusCode::SERVICE_UNAVAILABLE.into_response();
+        }
+    };
+
+    if repaired {
+        if let Ok(mut memory) = METASTATE.write() {
+            for key in memory.placed(&reporter, &payload.id) {
+                let Some(mut chunk) = memory.get(&key) else {
+                    continue;
+                };
+                let Some(index) = chunk
+                    .placements()
+                    .iter()
+                    .position(|(name, host)| *name == payload.id && host.ip == reporter)
+                else {
+                    continue;
+                };
+                chunk.hosts[index].status = Status::Healthy;
+                chunk.version += 1;
+                self::log_chunk(&chunk);
+                memory.insert(chunk);
+            }
+        }
+        return Json(MetaChunk { id: payload.id }).into_response();
+    }
 
     StatusCode::INTERNAL_SERVER_ERROR.into_response()
 }
 
-fn delete_remote_chunk(chunk_id: String, remote_ip: String, token: &str) {
-    let data = MetaChunk {
+enum Repair {
+    /// healthy replicas that can send a good copy of the chunk
+    Replica(Vec<String>),
+    /// the erasure coded chunk and the index of the shard that has to be rebuilt
+    Shard(MetaStore, usize),
+}
+
+/// the worker nodes that sent a heartbeat recently enough to be considered alive
+pub fn workers() -> Vec<String> {
+    let mut heartbeats = HashMap::new();
+    let now = chrono::Utc::now();
+
+    if let Ok(x) = HEARTBEAT.lock() {
+        heartbeats = x.clone();
+    }
+
+    heartbeats
+        .into_iter()
+        .filter(|v| (now - v.1).num_minutes() <= TIMEOUT_IN_MINUTES)
+        .map(|v| v.0)
+        .collect()
+}
+
+/// a copy of the metadata of every chunk, for reports that don't need to hold the lock
+pub fn chunks() -> Vec<MetaStore> {
+    let mut chunks = vec![];
+    if let Ok(memory) = METASTATE.read() {
+        memory.each(&mut |x| chunks.push(x.clone()));
+    }
+    chunks
+}
+
+/// runs a report over the metadata of every chunk without copying it
+pub fn report<T>(query: impl FnOnce(&dyn Backend) -> T) -> Option<T> {
+    METASTATE.read().ok().map(|x| query(x.as_ref()))
+}
+
+/// worker nodes are tracked by their ip, they are all expected to listen on the default port
+/// appends a changed chunk to the snapshot and to the replicated log
+fn log_chunk(chunk: &MetaStore) {
+    self::journal("snapshot", &format!("{}", json!(chunk)));
+    raft::record(Op::Chunk(chunk.clone()));
+}
+
+fn log_prune(file_id: &str) {
+    let tombstone = Tombstone {
+        file_id: file_id.to_string(),
+    };
+    self::journal("prune", &format!("{}", json!(tombstone)));
+    raft::record(Op::Prune(file_id.to_string()));
+}
+
+/// adds or updates a chunk replicated from the leader
+pub fn apply_chunk(chunk: MetaStore) {
+    if let Ok(mut memory) = METASTATE.write() {
+        self::journal("snapshot", &format!("{}", json!(chunk)));
+        memory.insert(chunk);
+    }
+}
+
+/// drops the chunks of a file removed on the leader, which already deleted them from the workers
+pub fn apply_prune(file_id: &str) {
+    if let Ok(mut memory) = METASTATE.write() {
+        let tombstone = Tombstone {
+            file_id: file_id.to_string(),
+        };
+        self::journal("prune", &format!("{}", json!(tombstone)));
+        memory.remove_file(file_id);
+    }
+}
+
+/// adds the chunks rebuilt from the workers, logged like any other change
+pub fn recover_chunks(chunks: Vec<MetaStore>) {
+    for chunk in chunks {
+        self::apply_chunk(chunk.clone());
+        raft::record(Op::Chunk(chunk));
+    }
+}
+
+/// takes over all of the chunks of the leader, replacing the snapshot
+pub fn replace_chunks(chunks: Vec<MetaStore>) {
+    if let Ok(mut memory) = METASTATE.write() {
+        memory.clear();
+        memory.extend(chunks);
+    }
+    if let Err(e) = self::export_compacted_snapshot() {
+        warn!("unable to export the installed snapshot: {}", e);
+    }
+}
+
+pub fn worker_url(ip: &str) -> String {
+    format!("{}://{}:8888", tls::scheme(), ip)
+}
+
+fn repair_remote_chunk(
+    chunk_id: &str,
+    sources: Vec<String>,
+    target: &str,
+    files: Vec<MetaStore>,
+    config: &Config,
+) -> bool {
+    for source in sources {
+        let data = json!({
+            "id": chunk_id,
+            "target": self::worker_url(target),
+            "capability": auth::grant(&config.token, chunk_id, Operation::Store, files.clone()),
+        });
+
+        if auth::send(
+            config,
+            &format!("{}/send-chunk", self::worker_url(&source)),
+            data,
+        )
+        .is_ok()
+        {
+            info!(
+                "chunk ({}) re-replicated from {} to {}",
+                chunk_id, source, target
+            );
+            return true;
+        }
+        warn!(
+            "unable to re-replicate chunk ({}) from {}",
+            chunk_id, source
+        );
+    }
+    false
+}
+
+fn rebuild_remote_shard(
+    chunk: &MetaStore,
+    index: usize,
+    files: Vec<MetaStore>,
+    config: &Config,
+) -> bool {
+    let Policy::Erasure { data, parity } = chunk.policy else {
+        return false;
+    };
+
+    let shards: Vec<Option<Vec<u8>>> = chunk
+        .placements()
+        .iter()
+        .enumerate()
+        .map(|(i, (name, host))| {
+            if i == index || !matches!(host.status, Status::Healthy) {
+                return None;
+            }
+            worker::pull_chunk(&host.ip, name, config)
+        })
+        .collect();
+
+    let Some(shards) = erasure::reconstruct(shards, data, parity) else {
+        warn!(
+            "not enough shards left to rebuild ({})",
+            &chunk.shards[index]
+        );
+        return false;
+    };
+
+    let (name, host) = &chunk.placements()[index];
+    let capability = auth::grant(&config.token, name, Operation::Store, files);
+    if worker::push_chunk(
+        &host.ip,
+        name,
+        &shards[index],
+        chunk.codec,
+        Some(&capability),
+        config,
+    ) {
+        info!("shard ({}) rebuilt on {}", name, host.ip);
+        return true;
+    }
+    false
+}
+
+fn tag(name: &str, chunk: &MetaStore, file: Option<MetaStore>) -> TagChunk {
+    TagChunk {
+        id: name.to_string(),
+        file_id: chunk.file_id.to_string(),
+        file,
+    }
+}
+
+/// tells the workers which files (no longer) reference the chunks they already store
+fn tag_remote_chunks(tags: Vec<(String, TagChunk)>, config: &Config) {
+    for (remote_ip, data) in tags {
+        let id = data.id.to_string();
+        if auth::send(
+            config,
+            &format!("{}/tag-chunk", self::worker_url(&remote_ip)),
+            data,
+        )
+        .is_err()
+        {
+            warn!("unable to tag chunk ({}) on {}", &id, &remote_ip);
+        }
+    }
+}
+
+fn delete_remote_chunk(chunk_id: String, remote_ip: String, config: &Config) {
+    let data = DeleteChunk {
         id: chunk_id.clone(),
+        capability: Some(auth::grant(
+            &config.token,
+            &chunk_id,
+            Operation::Delete,
+            vec![],
+        )),
     };
 
-    if let Ok(_) = ureq::post(&format!("http://{}:8888/delete-chunk", remote_ip))
-        .set("x-rdfs-token", token)
-        .send_json(data)
+    if auth::send(
+        config,
+        &format!("{}/delete-chunk", self::worker_url(&remote_ip)),
+        data,
+    )
+    .is_ok()
     {
         info!("remote chunk deleted ({})", &chunk_id);
     } else {
@@ -333,9 +1129,17 @@ fn create_dummy_snapshot() {
     info!("generating dummy snapshot data...");
 
     let a = MetaStore {
+        file_id: String::new(),
         file_name: String::from("README.md"),
         hash: String::from("5c9d231c8b6d10f43fd0768ca80755d2"),
         chunk_id: 1,
+        chunk_hash: String::new(),
+        size: 0,
+        policy: Policy::Replicated,
+        codec: Codec::None,
+        shards: vec![],
+        wrapped_key: String::new(),
+        version: 0,
         hosts: vec![
             Host {
                 ip: String::from("192.168.1.80"),
@@ -349,9 +1153,17 @@ fn create_dummy_snapshot() {
     };
 
     let b = MetaStore {
+        file_id: String::new(),
         file_name: String::from("README.md"),
         hash: String::from("5c9d231c8b6d10f43fd0768ca80755d2"),
         chunk_id: 2,
+        chunk_hash: String::new(),
+        size: 0,
+        policy: Policy::Replicated,
+        codec: Codec::None,
+        shards: vec![],
+        wrapped_key: String::new(),
+        version: 0,
         hosts: vec![
             Host {
                 ip: String::from("192.168.1.81"),
@@ -365,9 +1177,17 @@ fn create_dummy_snapshot() {
     };
 
     let c = MetaStore {
+        file_id: String::new(),
         file_name: String::from("README.md"),
         hash: String::from("5c9d231c8b6d10f43fd0768ca80755d2"),
         chunk_id: 2,
+        chunk_hash: String::new(),
+        size: 0,
+        policy: Policy::Replicated,
+        codec: Codec::None,
+        shards: vec![],
+        wrapped_key: String::new(),
+        version: 0,
         hosts: vec![
             Host {
                 ip: String::from("192.168.1.82"),
@@ -396,10 +1216,26 @@ fn load_snapshot() {
 
     // self::create_dummy_snapshot();
 
-    let mut prune = Vec::new();
+    // the database already holds the chunks, a snapshot next to it is only imported into an empty one
+    if let Ok(memory) = METASTATE.read() {
+        if memory.durable() && !memory.is_empty() {
+            info!("total chunks in the metadata database: {}", memory.len());
+            return;
+        }
+    }
+
+    let mut prune: HashSet<String> = HashSet::new();
 
     if let Ok(v) = self::read_lines("prune") {
-        prune = v;
+        // older prune logs only hold the hash of the removed file, which was also its identity
+        prune = v
+            .into_iter()
+            .filter(|x| !x.is_empty())
+            .map(|x| match serde_json::from_str::<Tombstone>(&x) {
+                Ok(tombstone) => tombstone.file_id,
+                Err(_) => x,
+            })
+            .collect();
     }
 
     if Path::new("snapshot").exists() {
@@ -409,65 +1245,97 @@ fn load_snapshot() {
 
         let mut compactor: HashMap<(String, i32), MetaStore> = HashMap::new();
 
-        if let Ok(mut memory) = METASTATE.lock() {
-            for line in reader.lines() {
-                if let Ok(_line) = line {
-                    if let Ok(disk) = serde_json::from_str::<MetaStore>(&_line) {
-                        if !prune.contains(&disk.hash) {
-                            compactor
-                                .entry((disk.hash.to_string(), disk.chunk_id))
-                                .and_modify(|x| *x = disk.clone())
-                                .or_insert(disk);
-                        }
+        if let Ok(mut memory) = METASTATE.write() {
+            for line in reader.lines().map_while(Result::ok) {
+                if let Ok(mut disk) = serde_json::from_str::<MetaStore>(&line) {
+                    if disk.file_id.is_empty() {
+                        disk.file_id = disk.hash.to_string();
+                    }
+                    if !prune.contains(&disk.file_id) {
+                        compactor
+                            .entry((disk.file_id.to_string(), disk.chunk_id))
+                            .and_modify(|x| *x = disk.clone())
+                            .or_insert(disk);
                     }
                 }
             }
-            for (_, v) in compactor {
-                memory.push(v);
-            }
+            memory.extend(compactor.into_values().collect());
         }
 
-        if let Ok(memory) = METASTATE.lock() {
+        if let Ok(memory) = METASTATE.read() {
             info!(
                 "total chunks loaded into memory after compaction: {}",
                 memory.len()
-            )
+            );
+
+            // kept aside so the import isn't repeated, nor the snapshot mistaken for a current one
+            if memory.durable() {
+                let imported =
+                    std::fs::rename("snapshot", "snapshot.imported").and_then(|_| match Path::new(
+                        "prune",
+                    )
+                    .exists()
+                    {
+                        true => std::fs::remove_file("prune"),
+                        false => Ok(()),
+                    });
+                if let Err(e) = imported {
+                    error!("unable to set the imported snapshot aside: {}", e);
+                }
+            }
         }
     }
 }
 
 fn export_compacted_snapshot() -> Result<(), std::io::Error> {
+    if !JOURNALED.load(Ordering::SeqCst) {
+        return Ok(());
+    }
     info!("attempting to export compacted snapshot...");
     // FIX: we're ignoring any errors, at some point we need to consider
     // handling them
-    if let Ok(memory) = METASTATE.lock() {
+    if let Ok(memory) = METASTATE.read() {
         let mut w = File::create("snapshot.new")?;
-        for v in &*memory {
-            writeln!(&mut w, "{}", json!(v))?;
-        }
+        let mut written = Ok(());
+        memory.each(&mut |v| {
+            if written.is_ok() {
+                written = writeln!(&mut w, "{}", json!(v));
+            }
+        });
+        written?;
     }
-    std::fs::remove_file("snapshot")?;
     std::fs::rename("snapshot.new", "snapshot")?;
-    std::fs::remove_file("prune")?;
+    if Path::new("prune").exists() {
+        std::fs::remove_file("prune")?;
+    }
     Ok(())
 }
 
 fn append(f: &str, d: &str) {
-    let mut h = OpenOptions::new().write(true).append(true).open(f).unwrap();
+    let mut h = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(f)
+        .unwrap();
 
     if let Err(e) = writeln!(h, "{}", d) {
         warn!("unable to append to file: {}", e);
     }
 }
 
+/// appends to the snapshot or the prune log, unless the chunks are kept in the metadata database
+fn journal(f: &str, d: &str) {
+    if JOURNALED.load(Ordering::SeqCst) {
+        self::append(f, d);
+    }
+}
+
 fn read_lines(p: &str) -> Result<Vec<String>, std::io::Error> {
     let f = File::open(p)?;
     let r = BufReader::new(f);
     let mut v = Vec::new();
-    for l in r.lines() {
-        if let Ok(l) = l {
-            v.push(l);
-        }
+    for l in r.lines().map_while(Result::ok) {
+        v.push(l);
     }
     Ok(v)
 }
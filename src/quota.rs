@@ -0,0 +1,326 @@
+use axum::{
+    http::StatusCode,
+    response::{IntoResponse, Json, Response},
+    Extension,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap};
+use std::fs;
+use std::sync::Mutex;
+use tracing::{info, warn};
+
+use crate::acl;
+use crate::acl::Permission;
+use crate::auth::{Identity, Scope};
+use crate::catalog::Backend;
+use crate::master;
+use crate::master::MetaStore;
+use crate::raft;
+use crate::raft::Op;
+
+/// where the master keeps the quotas, one json line per subject
+const QUOTA_STORE: &str = "quotas";
+
+/// limits on the physical bytes of either `user:<owner>` or `dir:<path>`
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Quota {
+    pub subject: String,
+    /// uploads past the soft limit still succeed but come with a warning
+    pub soft: Option<u64>,
+    /// uploads past the hard limit are refused
+    pub hard: Option<u64>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Usage {
+    pub subject: String,
+    /// the bytes of the files as uploaded
+    pub logical: u64,
+    /// the bytes stored across the workers, i.e including replicas or parity shards
+    pub physical: u64,
+    pub soft: Option<u64>,
+    pub hard: Option<u64>,
+}
+
+#[derive(Deserialize, Serialize, Default)]
+pub struct UsageQuery {
+    /// every subject the caller may see when not set
+    pub subject: Option<String>,
+}
+
+lazy_static! {
+    static ref QUOTAS: Mutex<HashMap<String, Quota>> = Mutex::new(HashMap::new());
+}
+
+/// parses sizes like 512, 300K, 10M, 2G or 1T (powers of 1024)
+pub fn parse_size(value: &str) -> Option<u64> {
+    let value = value.trim().to_uppercase();
+    let value = value.trim_end_matches("IB").trim_end_matches('B');
+    let (amount, unit) = value.split_at(
+        value
+            .find(|x: char| !x.is_ascii_digit())
+            .unwrap_or(value.len()),
+    );
+    let amount: u64 = amount.parse().ok()?;
+    let shift = match unit {
+        "" => 0,
+        "K" => 10,
+        "M" => 20,
+        "G" => 30,
+        "T" => 40,
+        _ => return None,
+    };
+    amount.checked_mul(1 << shift)
+}
+
+pub fn format_size(bytes: u64) -> String {
+    let units = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < units.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    match unit {
+        0 => format!("{} B", bytes),
+        _ => format!("{:.1} {}", size, units[unit]),
+    }
+}
+
+fn valid(subject: &str) -> bool {
+    matches!(
+        subject.split_once(':'),
+        Some(("user" | "dir", name)) if !name.trim_matches('/').is_empty()
+    )
+}
+
+/// directories are named without leading or trailing slashes, like everywhere else
+fn normalise(subject: &str) -> String {
+    match subject.split_once(':') {
+        Some(("dir", path)) => format!("dir:{}", path.trim_matches('/')),
+        _ => subject.to_string(),
+    }
+}
+
+/// every directory the file is in, the root excluded
+fn directories(file: &str) -> Vec<String> {
+    acl::lineage(file)
+        .into_iter()
+        .skip(1)
+        .filter(|x| !x.is_empty())
+        .collect()
+}
+
+/* -------------------------------------------------------------------------------------------------
+usage is counted per file: a file is charged to its owner (see `acl::owner`, files from before ACLs
+existed have none) and to every directory it is in. Chunks are deduplicated across files, but each
+file referencing a chunk is still charged for it, so the usage doesn't change when someone else
+removes their copy of the same content.
+------------------------------------------------------------------------------------------------- */
+fn tally(chunks: &dyn Backend) -> HashMap<String, (u64, u64)> {
+    let mut owners: HashMap<String, Option<String>> = HashMap::new();
+    let mut usage: HashMap<String, (u64, u64)> = HashMap::new();
+
+    chunks.each(&mut |chunk| {
+        let owner = owners
+            .entry(chunk.file_name.to_string())
+            .or_insert_with(|| acl::owner(&chunk.file_name));
+        let subjects = owner.iter().map(|x| format!("user:{}", x)).chain(
+            self::directories(&chunk.file_name)
+                .into_iter()
+                .map(|x| format!("dir:{}", x)),
+        );
+
+        for subject in subjects {
+            let entry = usage.entry(subject).or_default();
+            entry.0 += chunk.size;
+            entry.1 += chunk.stored_size();
+        }
+    });
+    usage
+}
+
+/// checks a new upload against the quotas of its owner and directories, returning the warnings
+/// for soft quotas or the reason it is refused
+pub fn check(
+    chunks: &dyn Backend,
+    owner: &str,
+    file: &str,
+    upload: &[MetaStore],
+) -> Result<Vec<String>, String> {
+    let quotas = QUOTAS.lock().map(|x| x.clone()).unwrap_or_default();
+    let subjects: Vec<String> = std::iter::once(format!("user:{}", owner))
+        .chain(
+            self::directories(file)
+                .into_iter()
+                .map(|x| format!("dir:{}", x)),
+        )
+        .filter(|x| quotas.contains_key(x))
+        .collect();
+    if subjects.is_empty() {
+        return Ok(vec![]);
+    }
+
+    let usage = self::tally(chunks);
+    let added: u64 = upload.iter().map(|x| x.stored_size()).sum();
+    let mut warnings = vec![];
+
+    for subject in subjects {
+        let quota = &quotas[&subject];
+        let total = usage.get(&subject).map(|x| x.1).unwrap_or(0) + added;
+
+        if let Some(hard) = quota.hard.filter(|x| total > *x) {
+            return Err(format!(
+                "{} would use {} of its {} hard quota",
+                subject,
+                self::format_size(total),
+                self::format_size(hard)
+            ));
+        }
+        if let Some(soft) = quota.soft.filter(|x| total > *x) {
+            warnings.push(format!(
+                "{} uses {} of its {} soft quota",
+                subject,
+                self::format_size(total),
+                self::format_size(soft)
+            ));
+        }
+    }
+    Ok(warnings)
+}
+
+pub fn load_quotas() {
+    let loaded: Vec<Quota> = fs::read_to_string(QUOTA_STORE)
+        .unwrap_or_default()
+        .lines()
+        .filter_map(|x| serde_json::from_str(x).ok())
+        .collect();
+    info!("loaded {} quota(s)", loaded.len());
+
+    if let Ok(mut quotas) = QUOTAS.lock() {
+        *quotas = loaded
+            .into_iter()
+            .map(|x| (x.subject.to_string(), x))
+            .collect();
+    }
+}
+
+fn save_quotas(quotas: &HashMap<String, Quota>) {
+    self::write_quotas(quotas);
+    raft::record(Op::Quotas(quotas.values().cloned().collect()));
+}
+
+fn write_quotas(quotas: &HashMap<String, Quota>) {
+    let lines: Vec<String> = quotas
+        .values()
+        .filter_map(|x| serde_json::to_string(x).ok())
+        .collect();
+
+    if let Err(e) = fs::write(QUOTA_STORE, lines.join("\n") + "\n") {
+        warn!("unable to save the quotas: {}", e);
+    }
+}
+
+pub fn quotas() -> Vec<Quota> {
+    QUOTAS
+        .lock()
+        .map(|x| x.values().cloned().collect())
+        .unwrap_or_default()
+}
+
+/// takes over the quotas replicated from the leader
+pub fn replace_quotas(replaced: Vec<Quota>) {
+    if let Ok(mut quotas) = QUOTAS.lock() {
+        *quotas = replaced
+            .into_iter()
+            .map(|x| (x.subject.to_string(), x))
+            .collect();
+        self::write_quotas(&quotas);
+    }
+}
+
+/// sets the quota of a subject, a quota without any limit removes it
+pub async fn set_quota(Json(mut payload): Json<Quota>) -> Response {
+    if !self::valid(&payload.subject) {
+        return StatusCode::BAD_REQUEST.into_response();
+    }
+    if let (Some(soft), Some(hard)) = (payload.soft, payload.hard) {
+        if soft > hard {
+            return (
+                StatusCode::BAD_REQUEST,
+                "the soft quota can't be larger than the hard quota",
+            )
+                .into_response();
+        }
+    }
+    payload.subject = self::normalise(&payload.subject);
+
+    let Ok(mut quotas) = QUOTAS.lock() else {
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    };
+    match (payload.soft, payload.hard) {
+        (None, None) => {
+            quotas.remove(&payload.subject);
+        }
+        _ => {
+            quotas.insert(payload.subject.to_string(), payload.clone());
+        }
+    }
+    self::save_quotas(&quotas);
+
+    info!(
+        "quota of [{}] set to soft {:?}, hard {:?}",
+        payload.subject, payload.soft, payload.hard
+    );
+    Json(payload).into_response()
+}
+
+/// reports the usage of the caller and of every directory with a quota, admins see every owner
+pub async fn usage(
+    Extension(identity): Extension<Identity>,
+    Json(payload): Json<UsageQuery>,
+) -> Response {
+    let admin = identity.allows(Scope::Admin);
+    let user = format!("user:{}", identity.name);
+    let quotas = QUOTAS.lock().map(|x| x.clone()).unwrap_or_default();
+    let usage = master::report(self::tally).unwrap_or_default();
+
+    let subjects: BTreeSet<String> = match payload.subject {
+        Some(subject) if self::valid(&subject) => BTreeSet::from([self::normalise(&subject)]),
+        Some(_) => return StatusCode::BAD_REQUEST.into_response(),
+        None => quotas
+            .keys()
+            .cloned()
+            .chain([user.to_string()])
+            .chain(
+                usage
+                    .keys()
+                    .filter(|x| admin && x.starts_with("user:"))
+                    .cloned(),
+            )
+            .collect(),
+    };
+
+    let visible = |subject: &str| match subject.split_once(':') {
+        _ if admin || subject == user => true,
+        Some(("dir", path)) => acl::permitted(&identity, path, Permission::Read),
+        _ => false,
+    };
+    let report: Vec<Usage> = subjects
+        .into_iter()
+        .filter(|x| visible(x))
+        .map(|x| {
+            let (logical, physical) = usage.get(&x).copied().unwrap_or_default();
+            let quota = quotas.get(&x);
+            Usage {
+                logical,
+                physical,
+                soft: quota.and_then(|y| y.soft),
+                hard: quota.and_then(|y| y.hard),
+                subject: x,
+            }
+        })
+        .collect();
+
+    Json(report).into_response()
+}
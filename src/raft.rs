@@ -0,0 +1,1092 @@
+use axum::{
+    extract::Request,
+    http::{header, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Json, Response},
+};
+use rand::Rng;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+use crate::acl;
+use crate::acl::Acl;
+use crate::auth;
+use crate::auth::TokenEntry;
+use crate::config::Config;
+use crate::master;
+use crate::master::MetaStore;
+use crate::quota;
+use crate::quota::Quota;
+use crate::snapshot;
+use crate::snapshot::Snapshot;
+
+/// where a master keeps its term, its vote and how far it got through the log
+const RAFT_STATE: &str = "raft";
+/// the replicated operations that weren't compacted yet, one json line per entry
+const RAFT_LOG: &str = "oplog";
+const MIN_ELECTION_TIMEOUT_IN_MILLIS: u64 = 1500;
+const MAX_ELECTION_TIMEOUT_IN_MILLIS: u64 = 3000;
+/// how often the leader reaches out to every follower, even with nothing to replicate
+const HEARTBEAT_IN_MILLIS: u64 = 300;
+const TICK_IN_MILLIS: u64 = 50;
+const RPC_TIMEOUT_IN_MILLIS: u64 = 1000;
+/// installing ships all of the metadata, which takes a while on a large cluster
+const INSTALL_TIMEOUT_IN_SECONDS: u64 = 30;
+/// how long a write waits for a majority of the masters before giving up
+const COMMIT_TIMEOUT_IN_SECONDS: u64 = 5;
+const MAX_ENTRIES_PER_APPEND: usize = 128;
+/// once the log holds more entries than this, the applied ones are dropped
+const COMPACT_AFTER_ENTRIES: usize = 1000;
+/// the largest install a follower accepts
+pub const MAX_INSTALL_BODY: usize = 512 * 1024 * 1024;
+/// how many entries a shadow master gets at once
+const MAX_ENTRIES_PER_FEED: u64 = 1024;
+
+/// a change to the metadata, applied in the same order on every master
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(rename_all = "lowercase")]
+pub enum Op {
+    /// a chunk was added or one of its hosts changed status
+    Chunk(MetaStore),
+    /// every chunk of the file with this id is gone
+    Prune(String),
+    /// the token, ACL, quota and snapshot stores are small enough to be replicated as a whole
+    Tokens(Vec<TokenEntry>),
+    Acls(Vec<Acl>),
+    Quotas(Vec<Quota>),
+    Snapshots(Vec<Snapshot>),
+    /// appended by a new leader, once it is applied the leader has caught up with the log
+    Noop,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Entry {
+    pub term: u64,
+    pub index: u64,
+    pub op: Op,
+}
+
+/// everything the masters replicate, sent to followers that can't catch up from the log
+#[derive(Deserialize, Serialize, Default)]
+pub struct Metadata {
+    pub chunks: Vec<MetaStore>,
+    pub tokens: Vec<TokenEntry>,
+    pub acls: Vec<Acl>,
+    pub quotas: Vec<Quota>,
+    #[serde(default)]
+    pub snapshots: Vec<Snapshot>,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct VoteRequest {
+    pub term: u64,
+    pub candidate: String,
+    pub last_index: u64,
+    pub last_term: u64,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct VoteReply {
+    pub term: u64,
+    pub granted: bool,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct AppendRequest {
+    pub term: u64,
+    pub leader: String,
+    pub prev_index: u64,
+    pub prev_term: u64,
+    pub entries: Vec<Entry>,
+    pub commit: u64,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct AppendReply {
+    pub term: u64,
+    pub success: bool,
+    /// the index the leader should continue from, 0 asks for the metadata to be installed
+    pub next: u64,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct InstallRequest {
+    pub term: u64,
+    pub leader: String,
+    /// the metadata holds every entry up to and including this one
+    pub index: u64,
+    pub index_term: u64,
+    /// the metadata may also hold entries past `index` the leader already executed
+    pub executed: u64,
+    pub metadata: Metadata,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct Tail {
+    /// the last entry the shadow master applied
+    pub since: u64,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct Feed {
+    /// set when the shadow master has to start over from a copy of all the metadata
+    pub metadata: Option<Metadata>,
+    /// the entry the metadata is up to
+    pub index: u64,
+    pub entries: Vec<Entry>,
+}
+
+#[derive(Deserialize, Serialize, Default)]
+struct Persistent {
+    term: u64,
+    voted_for: Option<String>,
+    applied: u64,
+    executed: u64,
+    offset: u64,
+    offset_term: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Role {
+    Follower,
+    Candidate,
+    Leader,
+}
+
+/// what the leader knows about a follower
+#[derive(Default)]
+struct Peer {
+    next: u64,
+    matched: u64,
+    contact: Option<Instant>,
+    sent: Option<Instant>,
+    /// whether the follower got the metadata from before the first entry of the log
+    installed: bool,
+}
+
+struct Node {
+    id: String,
+    config: Config,
+    peers: HashMap<String, Peer>,
+    role: Role,
+    term: u64,
+    voted_for: Option<String>,
+    leader: Option<String>,
+    /// the log starts right after this (compacted) entry
+    offset: u64,
+    offset_term: u64,
+    log: Vec<Entry>,
+    commit: u64,
+    /// every entry up to here is reflected in the metadata of this master
+    applied: u64,
+    /// the leader executes writes before they commit, entries up to here aren't applied again
+    executed: u64,
+    /// the noop of the current leader, writes wait until it is applied
+    ready: u64,
+    /// the metadata holds writes that never made it into the log and has to be installed again
+    stale: bool,
+    heard: Instant,
+    timeout: Duration,
+}
+
+/// where a write has to go
+enum Route {
+    Local,
+    Leader(u64),
+    Redirect(String),
+    Unavailable,
+}
+
+lazy_static! {
+    /// only set on masters with more than one endpoint configured
+    static ref RAFT: Mutex<Option<Node>> = Mutex::new(None);
+    /// committed entries and installs are applied one at a time
+    static ref APPLYING: Mutex<()> = Mutex::new(());
+}
+
+fn election_timeout() -> Duration {
+    Duration::from_millis(
+        rand::thread_rng()
+            .gen_range(MIN_ELECTION_TIMEOUT_IN_MILLIS..MAX_ELECTION_TIMEOUT_IN_MILLIS),
+    )
+}
+
+impl Node {
+    fn last_index(&self) -> u64 {
+        self.offset + self.log.len() as u64
+    }
+
+    fn last_term(&self) -> u64 {
+        self.log.last().map(|x| x.term).unwrap_or(self.offset_term)
+    }
+
+    /// none for compacted entries and entries past the end of the log
+    fn term_at(&self, index: u64) -> Option<u64> {
+        if index == self.offset {
+            return Some(self.offset_term);
+        }
+        if index < self.offset {
+            return None;
+        }
+        self.log
+            .get((index - self.offset - 1) as usize)
+            .map(|x| x.term)
+    }
+
+    fn entries(&self, from: u64, to: u64) -> Vec<Entry> {
+        let from = (from.max(self.offset + 1) - self.offset - 1) as usize;
+        let to = (to.min(self.last_index()) - self.offset) as usize;
+        self.log
+            .get(from..to)
+            .map(|x| x.to_vec())
+            .unwrap_or_default()
+    }
+
+    fn majority(&self) -> usize {
+        let masters = self.peers.len() + 1;
+        masters / 2 + 1
+    }
+
+    fn become_follower(&mut self, term: u64) {
+        if term > self.term {
+            self.term = term;
+            self.voted_for = None;
+            self.leader = None;
+        }
+        if self.role == Role::Leader {
+            info!("stepping down as leader in term {}", self.term);
+        }
+        self.role = Role::Follower;
+        self.persist();
+    }
+
+    fn become_leader(&mut self) {
+        info!("elected leader for term {}", self.term);
+        self.role = Role::Leader;
+        self.leader = Some(self.id.to_string());
+
+        let next = self.last_index() + 1;
+        for peer in self.peers.values_mut() {
+            *peer = Peer {
+                next,
+                contact: Some(Instant::now()),
+                ..Default::default()
+            };
+        }
+        self.ready = self.push(Op::Noop);
+    }
+
+    fn push(&mut self, op: Op) -> u64 {
+        let entry = Entry {
+            term: self.term,
+            index: self.last_index() + 1,
+            op,
+        };
+        self::append_log(std::slice::from_ref(&entry));
+        self.log.push(entry);
+        // a master on its own commits right away
+        self.advance_commit();
+        self.last_index()
+    }
+
+    /// drops the entry at index and everything after it
+    fn truncate(&mut self, index: u64) {
+        if index <= self.executed {
+            warn!("dropping entries from {} that were already executed", index);
+            self.stale = true;
+        }
+        self.log.truncate((index - self.offset - 1) as usize);
+        self.rewrite_log();
+    }
+
+    /// the highest entry of the current term stored on a majority of the masters is committed
+    fn advance_commit(&mut self) {
+        for index in (self.commit + 1..=self.last_index()).rev() {
+            if self.term_at(index) != Some(self.term) {
+                break;
+            }
+            let stored = 1 + self.peers.values().filter(|x| x.matched >= index).count();
+            if stored >= self.majority() {
+                self.commit = index;
+                break;
+            }
+        }
+    }
+
+    fn compact(&mut self) {
+        if self.log.len() <= COMPACT_AFTER_ENTRIES || self.applied <= self.offset {
+            return;
+        }
+        let Some(term) = self.term_at(self.applied) else {
+            return;
+        };
+        self.log.drain(..(self.applied - self.offset) as usize);
+        self.offset = self.applied;
+        self.offset_term = term;
+        self.persist();
+        self.rewrite_log();
+    }
+
+    fn persist(&self) {
+        let state = Persistent {
+            term: self.term,
+            voted_for: self.voted_for.clone(),
+            applied: self.applied,
+            executed: self.executed,
+            offset: self.offset,
+            offset_term: self.offset_term,
+        };
+        // the vote must never be lost, so the state is replaced in one go
+        let written = fs::write(
+            format!("{}.new", RAFT_STATE),
+            serde_json::json!(state).to_string(),
+        )
+        .and_then(|_| fs::rename(format!("{}.new", RAFT_STATE), RAFT_STATE));
+        if let Err(e) = written {
+            warn!("unable to save the raft state: {}", e);
+        }
+    }
+
+    fn rewrite_log(&self) {
+        let lines: String = self
+            .log
+            .iter()
+            .filter_map(|x| serde_json::to_string(x).ok())
+            .map(|x| x + "\n")
+            .collect();
+        let written = fs::write(format!("{}.new", RAFT_LOG), lines)
+            .and_then(|_| fs::rename(format!("{}.new", RAFT_LOG), RAFT_LOG));
+        if let Err(e) = written {
+            warn!("unable to rewrite the operation log: {}", e);
+        }
+    }
+
+    fn reply(&self, success: bool, next: u64) -> AppendReply {
+        AppendReply {
+            term: self.term,
+            success,
+            next,
+        }
+    }
+
+    fn receive(&mut self, request: AppendRequest) -> AppendReply {
+        if request.term < self.term {
+            return self.reply(false, 0);
+        }
+        if request.term > self.term || self.role != Role::Follower {
+            self.become_follower(request.term);
+        }
+        self.leader = Some(request.leader);
+        self.heard = Instant::now();
+
+        if self.stale {
+            return self.reply(false, 0);
+        }
+        let last = self.last_index();
+        if request.prev_index > last {
+            return self.reply(false, last + 1);
+        }
+        if request.prev_index >= self.offset
+            && self.term_at(request.prev_index) != Some(request.prev_term)
+        {
+            // skip back over the whole conflicting term instead of one entry per round trip
+            let conflict = self.term_at(request.prev_index);
+            let mut next = request.prev_index;
+            while next > self.offset + 1 && self.term_at(next - 1) == conflict {
+                next -= 1;
+            }
+            return self.reply(false, next);
+        }
+
+        let matched = request.prev_index + request.entries.len() as u64;
+        let mut appended = vec![];
+        for entry in request.entries {
+            if entry.index <= self.offset {
+                continue;
+            }
+            match self.term_at(entry.index) {
+                Some(term) if term == entry.term => continue,
+                Some(_) => self.truncate(entry.index),
+                None => {}
+            }
+            appended.push(entry);
+        }
+        self::append_log(&appended);
+        self.log.extend(appended);
+
+        if self.stale {
+            self.persist();
+            return self.reply(false, 0);
+        }
+        if request.commit > self.commit {
+            self.commit = request.commit.min(matched).max(self.commit);
+        }
+        self.reply(true, self.last_index() + 1)
+    }
+}
+
+fn append_log(entries: &[Entry]) {
+    if entries.is_empty() {
+        return;
+    }
+    let written = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(RAFT_LOG)
+        .and_then(|mut x| {
+            entries
+                .iter()
+                .try_for_each(|y| writeln!(x, "{}", serde_json::json!(y)))
+        });
+    if let Err(e) = written {
+        warn!("unable to append to the operation log: {}", e);
+    }
+}
+
+/* -------------------------------------------------------------------------------------------------
+every master keeps an operation log, a single master is always its own leader and only keeps it for
+the shadow masters. With more than one endpoint in RDFS_ENDPOINT the masters replicate it with Raft.
+The metadata files of every master (snapshot, tokens, acls and quotas) always reflect the applied
+entries, so a restarted master only has to load them along with its term, vote and log. Entries
+are idempotent (chunks are upserts and the stores are replaced as a whole), replaying a few that
+were applied already is harmless.
+
+Writes only happen on the leader, which executes them right away and appends them to the log, the
+request then waits for the entries to commit. Should such an entry be dropped later on (the leader
+lost its majority before it committed) that master's metadata is marked as stale and the next
+leader installs all of its metadata on it.
+------------------------------------------------------------------------------------------------- */
+pub fn start(config: &Config) -> Result<(), String> {
+    let id = match config.endpoints.len() {
+        0 | 1 => config.endpoint.to_string(),
+        _ => config
+            .advertise
+            .clone()
+            .filter(|x| config.endpoints.contains(x))
+            .ok_or("RDFS_ADVERTISE has to be set to one of the endpoints in RDFS_ENDPOINT")?,
+    };
+
+    let state: Persistent = fs::read_to_string(RAFT_STATE)
+        .ok()
+        .and_then(|x| serde_json::from_str(&x).ok())
+        .unwrap_or_default();
+    let log: Vec<Entry> = fs::read_to_string(RAFT_LOG)
+        .unwrap_or_default()
+        .lines()
+        .filter_map(|x| serde_json::from_str::<Entry>(x).ok())
+        .filter(|x| x.index > state.offset)
+        .enumerate()
+        .take_while(|(i, x)| x.index == state.offset + 1 + *i as u64)
+        .map(|(_, x)| x)
+        .collect();
+
+    let peers: Vec<String> = config
+        .endpoints
+        .iter()
+        .filter(|x| **x != id)
+        .cloned()
+        .collect();
+    let node = Node {
+        id: id.to_string(),
+        config: config.clone(),
+        peers: peers
+            .iter()
+            .map(|x| (x.to_string(), Peer::default()))
+            .collect(),
+        role: Role::Follower,
+        term: state.term,
+        voted_for: state.voted_for,
+        leader: None,
+        offset: state.offset,
+        offset_term: state.offset_term,
+        log,
+        commit: state.applied,
+        applied: state.applied,
+        executed: state.executed,
+        ready: 0,
+        stale: false,
+        heard: Instant::now(),
+        timeout: self::election_timeout(),
+    };
+    node.rewrite_log();
+
+    if let Ok(mut raft) = RAFT.lock() {
+        *raft = Some(node);
+    }
+
+    std::thread::spawn(|| loop {
+        std::thread::sleep(Duration::from_millis(TICK_IN_MILLIS / 5));
+        self::apply_committed();
+    });
+    if peers.is_empty() {
+        if let Some(node) = RAFT.lock().ok().as_mut().and_then(|x| x.as_mut()) {
+            node.become_leader();
+        }
+        self::apply_committed();
+        return Ok(());
+    }
+
+    if let Some(node) = RAFT.lock().ok().as_ref().and_then(|x| x.as_ref()) {
+        info!(
+            "joining the masters as [{}] in term {} with {} log entries",
+            id,
+            node.term,
+            node.log.len()
+        );
+    }
+    std::thread::spawn(|| loop {
+        std::thread::sleep(Duration::from_millis(TICK_IN_MILLIS));
+        self::tick();
+    });
+    for peer in peers {
+        std::thread::spawn(move || loop {
+            std::thread::sleep(Duration::from_millis(TICK_IN_MILLIS));
+            self::replicate(&peer);
+        });
+    }
+    Ok(())
+}
+
+/// appends a write the leader just executed, any other master has now diverged from the log
+pub fn record(op: Op) {
+    let Ok(mut raft) = RAFT.lock() else {
+        return;
+    };
+    let Some(node) = raft.as_mut() else {
+        return;
+    };
+
+    if node.role != Role::Leader {
+        warn!("executed a write without being the leader, the metadata is stale");
+        node.stale = true;
+        return;
+    }
+    node.executed = node.push(op);
+    node.persist();
+}
+
+fn call<T: Serialize, R: DeserializeOwned>(
+    peer: &str,
+    route: &str,
+    config: &Config,
+    data: T,
+    timeout: Duration,
+) -> Option<R> {
+    auth::send_within(config, &format!("{}/{}", peer, route), data, timeout)
+        .ok()?
+        .into_json()
+        .ok()
+}
+
+/// starts an election once the leader hasn't been heard from in a while, the leader itself steps
+/// down when it can no longer reach a majority
+fn tick() {
+    let (request, peers, config) = {
+        let Ok(mut raft) = RAFT.lock() else {
+            return;
+        };
+        let Some(node) = raft.as_mut() else {
+            return;
+        };
+
+        if node.role == Role::Leader {
+            let window = Duration::from_millis(MAX_ELECTION_TIMEOUT_IN_MILLIS);
+            let reachable = 1 + node
+                .peers
+                .values()
+                .filter(|x| x.contact.is_some_and(|y| y.elapsed() < window))
+                .count();
+            if reachable < node.majority() {
+                warn!("lost contact with the majority of the masters");
+                node.become_follower(node.term);
+                node.leader = None;
+                node.heard = Instant::now();
+            }
+            return;
+        }
+        if node.heard.elapsed() < node.timeout {
+            return;
+        }
+
+        node.term += 1;
+        node.role = Role::Candidate;
+        node.voted_for = Some(node.id.to_string());
+        node.leader = None;
+        node.heard = Instant::now();
+        node.timeout = self::election_timeout();
+        node.persist();
+        info!("starting an election for term {}", node.term);
+
+        let request = VoteRequest {
+            term: node.term,
+            candidate: node.id.to_string(),
+            last_index: node.last_index(),
+            last_term: node.last_term(),
+        };
+        let peers: Vec<String> = node.peers.keys().cloned().collect();
+        (request, peers, node.config.clone())
+    };
+
+    let replies: Vec<VoteReply> = std::thread::scope(|s| {
+        let calls: Vec<_> = peers
+            .iter()
+            .map(|x| {
+                s.spawn(|| {
+                    self::call(
+                        x,
+                        "raft/vote",
+                        &config,
+                        &request,
+                        Duration::from_millis(RPC_TIMEOUT_IN_MILLIS),
+                    )
+                })
+            })
+            .collect();
+        calls
+            .into_iter()
+            .filter_map(|x| x.join().ok().flatten())
+            .collect()
+    });
+
+    let Ok(mut raft) = RAFT.lock() else {
+        return;
+    };
+    let Some(node) = raft.as_mut() else {
+        return;
+    };
+    if node.role != Role::Candidate || node.term != request.term {
+        return;
+    }
+    if let Some(term) = replies
+        .iter()
+        .map(|x| x.term)
+        .max()
+        .filter(|x| *x > node.term)
+    {
+        node.become_follower(term);
+        return;
+    }
+    if 1 + replies.iter().filter(|x| x.granted).count() >= node.majority() {
+        node.become_leader();
+    }
+}
+
+/// sends the follower what it is missing (or a heartbeat), falling back to installing all of the
+/// metadata when the entries it needs were compacted
+fn replicate(peer: &str) {
+    let (request, term, config) = {
+        let Ok(mut raft) = RAFT.lock() else {
+            return;
+        };
+        let Some(node) = raft.as_mut() else {
+            return;
+        };
+        if node.role != Role::Leader {
+            return;
+        }
+
+        let last = node.last_index();
+        let (offset, term, applied, commit) = (node.offset, node.term, node.applied, node.commit);
+        let Some(state) = node.peers.get_mut(peer) else {
+            return;
+        };
+        let due = state
+            .sent
+            .is_none_or(|x| x.elapsed() >= Duration::from_millis(HEARTBEAT_IN_MILLIS));
+        if !due && state.next > last {
+            return;
+        }
+        state.sent = Some(Instant::now());
+
+        let next = state.next;
+        let request = match next <= offset || (next == 1 && !state.installed) {
+            true => Err((applied, node.term_at(applied).unwrap_or_default())),
+            false => Ok(AppendRequest {
+                term,
+                leader: node.id.to_string(),
+                prev_index: next - 1,
+                prev_term: node.term_at(next - 1).unwrap_or_default(),
+                entries: node.entries(next, next + MAX_ENTRIES_PER_APPEND as u64 - 1),
+                commit,
+            }),
+        };
+        (request, term, node.config.clone())
+    };
+
+    let (reply, matched): (Option<AppendReply>, u64) = match request {
+        Ok(request) => {
+            let matched = request.prev_index + request.entries.len() as u64;
+            let timeout = Duration::from_millis(RPC_TIMEOUT_IN_MILLIS);
+            (
+                self::call(peer, "raft/append", &config, &request, timeout),
+                matched,
+            )
+        }
+        Err((index, index_term)) => {
+            let metadata = self::metadata();
+            let Some((leader, executed)) = RAFT
+                .lock()
+                .ok()
+                .and_then(|x| x.as_ref().map(|y| (y.id.to_string(), y.executed)))
+            else {
+                return;
+            };
+            info!("installing the metadata up to {} on [{}]", index, peer);
+            let request = InstallRequest {
+                term,
+                leader,
+                index,
+                index_term,
+                executed,
+                metadata,
+            };
+            let timeout = Duration::from_secs(INSTALL_TIMEOUT_IN_SECONDS);
+            (
+                self::call(peer, "raft/install", &config, &request, timeout),
+                index,
+            )
+        }
+    };
+    let Some(reply) = reply else {
+        return;
+    };
+
+    let Ok(mut raft) = RAFT.lock() else {
+        return;
+    };
+    let Some(node) = raft.as_mut() else {
+        return;
+    };
+    if reply.term > node.term {
+        node.become_follower(reply.term);
+        return;
+    }
+    if node.role != Role::Leader || node.term != term {
+        return;
+    }
+    let last = node.last_index();
+    let Some(state) = node.peers.get_mut(peer) else {
+        return;
+    };
+    state.contact = Some(Instant::now());
+    match reply.success {
+        true => {
+            state.matched = state.matched.max(matched);
+            state.next = state.matched + 1;
+            state.installed = true;
+            node.advance_commit();
+        }
+        false => {
+            state.next = reply.next.clamp(1, last + 1);
+            state.installed &= reply.next > 0;
+        }
+    }
+}
+
+/// a copy of everything the masters replicate
+fn metadata() -> Metadata {
+    Metadata {
+        chunks: master::chunks(),
+        tokens: auth::tokens(),
+        acls: acl::acls(),
+        quotas: quota::quotas(),
+        snapshots: snapshot::snapshots(),
+    }
+}
+
+pub fn execute(op: Op) {
+    match op {
+        Op::Chunk(chunk) => master::apply_chunk(chunk),
+        Op::Prune(file_id) => master::apply_prune(&file_id),
+        Op::Tokens(tokens) => auth::replace_tokens(tokens),
+        Op::Acls(acls) => acl::replace_acls(acls),
+        Op::Quotas(quotas) => quota::replace_quotas(quotas),
+        Op::Snapshots(snapshots) => snapshot::replace_snapshots(snapshots),
+        Op::Noop => {}
+    }
+}
+
+fn apply_committed() {
+    let Ok(_guard) = APPLYING.lock() else {
+        return;
+    };
+    let (entries, executed) = {
+        let Ok(raft) = RAFT.lock() else {
+            return;
+        };
+        let Some(node) = raft.as_ref() else {
+            return;
+        };
+        if node.applied >= node.commit {
+            return;
+        }
+        (node.entries(node.applied + 1, node.commit), node.executed)
+    };
+
+    for entry in &entries {
+        if entry.index > executed {
+            self::execute(entry.op.clone());
+        }
+    }
+
+    if let (Ok(mut raft), Some(last)) = (RAFT.lock(), entries.last()) {
+        if let Some(node) = raft.as_mut() {
+            node.applied = node.applied.max(last.index);
+            node.executed = node.executed.max(node.applied);
+            node.persist();
+            node.compact();
+        }
+    }
+}
+
+pub async fn vote(Json(request): Json<VoteRequest>) -> Response {
+    let Ok(mut raft) = RAFT.lock() else {
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    };
+    let Some(node) = raft.as_mut() else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+
+    if request.term > node.term {
+        node.become_follower(request.term);
+    }
+    // only candidates with a log at least as complete as ours can become leader
+    let complete = (request.last_term, request.last_index) >= (node.last_term(), node.last_index());
+    let granted = request.term == node.term
+        && complete
+        && node
+            .voted_for
+            .as_ref()
+            .is_none_or(|x| *x == request.candidate);
+
+    if granted {
+        info!("voted for [{}] in term {}", request.candidate, node.term);
+        node.voted_for = Some(request.candidate);
+        node.heard = Instant::now();
+        node.persist();
+    }
+    Json(VoteReply {
+        term: node.term,
+        granted,
+    })
+    .into_response()
+}
+
+pub async fn append(Json(request): Json<AppendRequest>) -> Response {
+    let Ok(mut raft) = RAFT.lock() else {
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    };
+    match raft.as_mut() {
+        Some(node) => Json(node.receive(request)).into_response(),
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+pub async fn install(Json(request): Json<InstallRequest>) -> Response {
+    match tokio::task::spawn_blocking(move || self::receive_install(request)).await {
+        Ok(Some(reply)) => Json(reply).into_response(),
+        Ok(None) => StatusCode::NOT_FOUND.into_response(),
+        Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    }
+}
+
+fn receive_install(request: InstallRequest) -> Option<AppendReply> {
+    {
+        let mut raft = RAFT.lock().ok()?;
+        let node = raft.as_mut()?;
+        if request.term < node.term {
+            return Some(node.reply(false, 0));
+        }
+        if request.term > node.term || node.role != Role::Follower {
+            node.become_follower(request.term);
+        }
+        node.leader = Some(request.leader.to_string());
+        node.heard = Instant::now();
+        if !node.stale && request.index < node.applied {
+            return Some(node.reply(true, node.last_index() + 1));
+        }
+    }
+
+    let _guard = APPLYING.lock().ok()?;
+    self::replace(request.metadata);
+
+    let mut raft = RAFT.lock().ok()?;
+    let node = raft.as_mut()?;
+    // entries past the installed ones are kept as long as the log agrees with the leader's
+    match node.term_at(request.index) {
+        Some(term) if term == request.index_term => {
+            node.log.retain(|x| x.index > request.index);
+        }
+        _ => node.log.clear(),
+    }
+    node.offset = request.index;
+    node.offset_term = request.index_term;
+    node.commit = node.commit.max(request.index);
+    node.applied = request.index;
+    node.executed = request.executed.max(request.index);
+    node.stale = false;
+    node.heard = Instant::now();
+    node.persist();
+    node.rewrite_log();
+
+    info!(
+        "installed the metadata up to {} from [{}]",
+        request.index, request.leader
+    );
+    Some(node.reply(true, node.last_index() + 1))
+}
+
+/// takes over all of the metadata of another master
+pub fn replace(metadata: Metadata) {
+    master::replace_chunks(metadata.chunks);
+    auth::replace_tokens(metadata.tokens);
+    acl::replace_acls(metadata.acls);
+    quota::replace_quotas(metadata.quotas);
+    snapshot::replace_snapshots(metadata.snapshots);
+}
+
+/// hands the committed entries after `since` to a shadow master, or all of the metadata when
+/// those entries were compacted (or it is just starting out)
+pub async fn feed(Json(payload): Json<Tail>) -> Response {
+    let (since, install, entries) = {
+        let Ok(raft) = RAFT.lock() else {
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        };
+        let Some(node) = raft.as_ref() else {
+            return StatusCode::NOT_FOUND.into_response();
+        };
+        let install =
+            payload.since == 0 || payload.since < node.offset || payload.since > node.last_index();
+        match install {
+            true => (node.applied, true, vec![]),
+            false => (
+                payload.since,
+                false,
+                node.entries(
+                    payload.since + 1,
+                    node.commit.min(payload.since + MAX_ENTRIES_PER_FEED),
+                ),
+            ),
+        }
+    };
+
+    Json(Feed {
+        metadata: install.then(self::metadata),
+        index: since,
+        entries,
+    })
+    .into_response()
+}
+
+/// the routes that change the replicated metadata
+fn writes(path: &str) -> bool {
+    matches!(
+        path,
+        "/upload"
+            | "/remove"
+            | "/report-chunk"
+            | "/chmod"
+            | "/chown"
+            | "/create-token"
+            | "/revoke-token"
+            | "/set-quota"
+            | "/create-snapshot"
+            | "/restore-snapshot"
+            | "/delete-snapshot"
+    )
+}
+
+fn route() -> Route {
+    let Ok(raft) = RAFT.lock() else {
+        return Route::Unavailable;
+    };
+    let Some(node) = raft.as_ref() else {
+        return Route::Local;
+    };
+    match (node.role, &node.leader) {
+        (Role::Leader, _) if node.applied >= node.ready => Route::Leader(node.term),
+        (Role::Leader, _) => Route::Unavailable,
+        (_, Some(leader)) => Route::Redirect(leader.to_string()),
+        (_, None) => Route::Unavailable,
+    }
+}
+
+/// none once the master is no longer the leader of the term
+fn committed(term: u64, index: u64) -> Option<bool> {
+    let raft = RAFT.lock().ok()?;
+    let node = raft.as_ref()?;
+    if node.role != Role::Leader || node.term != term {
+        return None;
+    }
+    Some(node.commit >= index)
+}
+
+fn last_index() -> u64 {
+    RAFT.lock()
+        .ok()
+        .and_then(|x| x.as_ref().map(|y| y.last_index()))
+        .unwrap_or_default()
+}
+
+/* -------------------------------------------------------------------------------------------------
+reads are answered by any master from its own metadata (which may lag behind the leader for a
+moment), writes are redirected to the leader. Clients and workers follow the redirect themselves
+(see `auth::send_master`) since ureq won't resend the body of a POST. The answer to a write is only
+sent once its entries are committed, when that doesn't happen in time the write may or may not
+have been applied.
+------------------------------------------------------------------------------------------------- */
+pub async fn forward(request: Request, next: Next) -> Response {
+    if !self::writes(request.uri().path()) {
+        return next.run(request).await;
+    }
+
+    let term = match self::route() {
+        Route::Local => return next.run(request).await,
+        Route::Leader(term) => term,
+        Route::Redirect(leader) => {
+            let path = request
+                .uri()
+                .path_and_query()
+                .map(|x| x.to_string())
+                .unwrap_or_default();
+            return (
+                StatusCode::TEMPORARY_REDIRECT,
+                [(header::LOCATION, format!("{}{}", leader, path))],
+            )
+                .into_response();
+        }
+        Route::Unavailable => {
+            return (
+                StatusCode::SERVICE_UNAVAILABLE,
+                [(header::RETRY_AFTER, "1")],
+                "no leader has been elected yet",
+            )
+                .into_response();
+        }
+    };
+
+    let response = next.run(request).await;
+    let index = self::last_index();
+    let deadline = Instant::now() + Duration::from_secs(COMMIT_TIMEOUT_IN_SECONDS);
+
+    while Instant::now() < deadline {
+        match self::committed(term, index) {
+            Some(true) => return response,
+            Some(false) => tokio::time::sleep(Duration::from_millis(TICK_IN_MILLIS / 5)).await,
+            None => break,
+        }
+    }
+    warn!("entry {} wasn't committed in time", index);
+    (
+        StatusCode::SERVICE_UNAVAILABLE,
+        "the change couldn't be replicated to a majority of the masters",
+    )
+        .into_response()
+}
@@ -0,0 +1,166 @@
+use axum::{
+    extract::Request,
+    http::{header, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use serde_json::json;
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use tracing::{error, info, warn};
+
+use crate::auth;
+use crate::config;
+use crate::config::Config;
+use crate::master;
+use crate::master::{MetaStore, Status};
+use crate::worker::StoredChunk;
+
+/// how long the master waits for the workers to check in, they send a heartbeat every 4 seconds
+const RECOVERY_WINDOW_IN_SECONDS: u64 = 10;
+
+/// set until the chunks reported by the workers have been added back
+static RECOVERING: AtomicBool = AtomicBool::new(false);
+
+/* -------------------------------------------------------------------------------------------------
+every worker keeps the metadata of the files referencing a chunk in the sidecar next to it, so if
+the snapshot of the master is lost (or damaged) the metadata of the chunks can be rebuilt from the
+workers. A recovering master starts out like any other, waits for the workers to send their
+heartbeats, asks each of them for the chunks it stores and adds back every chunk it doesn't know
+about. Only the chunks are recovered, the tokens, ACLs and quotas have to come from a backup.
+------------------------------------------------------------------------------------------------- */
+pub async fn init(port: &i16) {
+    if let Some(config) = config::get() {
+        if config.endpoints.len() > 1 {
+            error!("Error: recover a single master and let the others install its metadata, RDFS_ENDPOINT lists more than one");
+            return;
+        }
+
+        RECOVERING.store(true, Ordering::SeqCst);
+        tokio::task::spawn_blocking(move || background_recovery(config));
+    }
+    master::init(port).await
+}
+
+/// holds off everything but the heartbeats of the workers until the recovery is done
+pub async fn guard(request: Request, next: Next) -> Response {
+    if RECOVERING.load(Ordering::SeqCst) && request.uri().path() != "/heartbeat" {
+        return (
+            StatusCode::SERVICE_UNAVAILABLE,
+            [(header::RETRY_AFTER, RECOVERY_WINDOW_IN_SECONDS.to_string())],
+            "the master is recovering its metadata from the workers",
+        )
+            .into_response();
+    }
+    next.run(request).await
+}
+
+fn background_recovery(config: Config) {
+    info!(
+        "waiting {}s for the workers to check in before recovering...",
+        RECOVERY_WINDOW_IN_SECONDS
+    );
+    std::thread::sleep(Duration::from_secs(RECOVERY_WINDOW_IN_SECONDS));
+
+    let mut reports: HashMap<String, Vec<StoredChunk>> = HashMap::new();
+    for worker in master::workers() {
+        let listed = auth::send(
+            &config,
+            &format!("{}/list-chunks", master::worker_url(&worker)),
+            json!({}),
+        )
+        .and_then(|x| x.into_json::<Vec<StoredChunk>>().map_err(|e| e.to_string()));
+
+        match listed {
+            Ok(chunks) => {
+                info!("worker [{}] holds {} chunk(s)", worker, chunks.len());
+                reports.insert(worker, chunks);
+            }
+            Err(e) => warn!("unable to list the chunks of worker [{}]: {}", worker, e),
+        }
+    }
+    if reports.is_empty() {
+        warn!("no worker answered, there is nothing to recover from");
+    }
+
+    let unknown = reports
+        .values()
+        .flatten()
+        .filter(|x| x.files.is_empty())
+        .count();
+    if unknown > 0 {
+        warn!(
+            "{} chunk(s) don't know which files they belong to and can't be recovered",
+            unknown
+        );
+    }
+
+    // the metadata the master still has wins, unless a worker was sent a newer version of it
+    let known: HashMap<(String, i32), u64> = master::chunks()
+        .into_iter()
+        .map(|x| ((x.file_id, x.chunk_id), x.version))
+        .collect();
+    let recovered: Vec<MetaStore> = self::rebuild(&reports)
+        .into_iter()
+        .filter(|x| {
+            known
+                .get(&(x.file_id.to_string(), x.chunk_id))
+                .is_none_or(|version| *version < x.version)
+        })
+        .collect();
+    let files: HashSet<&str> = recovered.iter().map(|x| x.file_id.as_str()).collect();
+    info!(
+        "recovered {} chunk(s) of {} file(s) from {} worker(s)",
+        recovered.len(),
+        files.len(),
+        reports.len()
+    );
+
+    master::recover_chunks(recovered);
+    RECOVERING.store(false, Ordering::SeqCst);
+}
+
+/* -------------------------------------------------------------------------------------------------
+a chunk may be reported by several workers (its replicas or shards) each with the files they were
+told about, the newest version of every (file, chunk) wins. The hosts come from that metadata: a
+worker that holds the chunk (or its shard) is healthy, one that answered without it is dead and the
+ones that didn't answer at all are left as they were recorded.
+------------------------------------------------------------------------------------------------- */
+fn rebuild(reports: &HashMap<String, Vec<StoredChunk>>) -> Vec<MetaStore> {
+    let mut latest: HashMap<(String, i32), MetaStore> = HashMap::new();
+    let mut held: HashSet<(&str, &str)> = HashSet::new();
+
+    for (worker, chunks) in reports {
+        for chunk in chunks {
+            held.insert((worker, &chunk.id));
+            for file in chunk.files.iter() {
+                let key = (file.file_id.to_string(), file.chunk_id);
+                match latest.get(&key) {
+                    Some(known) if known.version >= file.version => {}
+                    _ => {
+                        latest.insert(key, file.clone());
+                    }
+                }
+            }
+        }
+    }
+
+    let mut chunks: Vec<MetaStore> = latest
+        .into_values()
+        .map(|mut chunk| {
+            for (i, (name, host)) in chunk.placements().into_iter().enumerate() {
+                if held.contains(&(host.ip.as_str(), name.as_str())) {
+                    chunk.hosts[i].status = Status::Healthy;
+                } else if reports.contains_key(&host.ip) {
+                    chunk.hosts[i].status = Status::Dead;
+                }
+            }
+            chunk
+        })
+        .collect();
+    chunks.sort_by(|a, b| {
+        (&a.file_name, &a.file_id, a.chunk_id).cmp(&(&b.file_name, &b.file_id, b.chunk_id))
+    });
+    chunks
+}
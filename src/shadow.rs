@@ -0,0 +1,117 @@
+use axum::http::StatusCode;
+use axum::middleware;
+use axum::response::{IntoResponse, Response};
+use axum::routing::post;
+use axum::Router;
+use std::fs;
+use std::time::Duration;
+use tracing::{error, info, warn};
+
+use crate::auth;
+use crate::config;
+use crate::config::Config;
+use crate::limit;
+use crate::master;
+use crate::raft;
+use crate::raft::{Feed, Tail};
+use crate::tls;
+
+/// where the shadow master keeps the last entry of the operation log it applied
+const SHADOW_STATE: &str = "shadow";
+/// how long the shadow master waits before asking the master for new entries
+const TAIL_INTERVAL_IN_MILLIS: u64 = 1000;
+
+/* -------------------------------------------------------------------------------------------------
+a shadow master keeps its own copy of the metadata by tailing the operation log of the master(s) in
+RDFS_ENDPOINT, so that files can still be listed and read while the master is down. It only ever
+lags behind: it serves whatever it has applied so far and refuses everything that would change the
+metadata, that is left to the master.
+------------------------------------------------------------------------------------------------- */
+pub async fn init(port: &i16) {
+    println!("{}", crate::LOGO);
+
+    if let Some(config) = config::get() {
+        if let Err(e) = master::load_metadata(&config) {
+            error!("Error: {}", e);
+            return;
+        }
+
+        info!("launching node in [shadow] mode on port {}...", port);
+
+        let tail = config.clone();
+        tokio::task::spawn_blocking(move || background_tail(tail));
+
+        let app = Router::new()
+            .route("/list", post(master::list))
+            .route("/get", post(master::get))
+            .route_layer(middleware::from_fn_with_state(
+                config.clone(),
+                limit::throttle,
+            ))
+            .route_layer(middleware::from_fn_with_state(
+                config.clone(),
+                auth::authorise,
+            ))
+            .fallback(read_only)
+            .layer(middleware::from_fn(limit::concurrency))
+            .with_state(config.clone());
+
+        tls::serve(app, port, &config).await.unwrap()
+    } else {
+        error!("Error: unable able to load the valid cluster configuration. Please make sure the ENV 'RDFS_ENDPOINT' and 'RDFS_TOKEN' are set");
+    }
+}
+
+async fn read_only() -> Response {
+    (
+        StatusCode::METHOD_NOT_ALLOWED,
+        "shadow masters are read-only, only list and get are served",
+    )
+        .into_response()
+}
+
+fn background_tail(config: Config) {
+    info!("tailing the operation log of the master...");
+
+    let mut since: u64 = fs::read_to_string(SHADOW_STATE)
+        .ok()
+        .and_then(|x| x.trim().parse().ok())
+        .unwrap_or(0);
+    loop {
+        match self::catch_up(&config, since) {
+            // keep going straight away while the master has more entries
+            Ok(index) if index > since => {
+                since = index;
+                continue;
+            }
+            Ok(_) => {}
+            Err(e) => warn!("unable to tail the operation log: {}", e),
+        }
+        std::thread::sleep(Duration::from_millis(TAIL_INTERVAL_IN_MILLIS));
+    }
+}
+
+/// applies the entries the master has after `since`, returns the last entry applied
+fn catch_up(config: &Config, since: u64) -> Result<u64, String> {
+    let feed: Feed = auth::send_master(config, "oplog", Tail { since })?
+        .into_json()
+        .map_err(|e| e.to_string())?;
+    let mut index = since;
+
+    if let Some(metadata) = feed.metadata {
+        info!("copying the metadata up to {} from the master", feed.index);
+        raft::replace(metadata);
+        index = feed.index;
+    }
+    for entry in feed.entries {
+        raft::execute(entry.op);
+        index = entry.index;
+    }
+
+    if index != since {
+        if let Err(e) = fs::write(SHADOW_STATE, index.to_string()) {
+            warn!("unable to save the shadow state: {}", e);
+        }
+    }
+    Ok(index)
+}
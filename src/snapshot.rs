@@ -0,0 +1,280 @@
+use axum::{
+    extract::State,
+    http::StatusCode,
+    response::{IntoResponse, Json, Response},
+    Extension,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::sync::Mutex;
+use tracing::{info, warn};
+
+use crate::acl;
+use crate::acl::Permission;
+use crate::auth::Identity;
+use crate::config::Config;
+use crate::master;
+use crate::raft;
+use crate::raft::Op;
+
+/// where the master keeps the snapshots, one json line per snapshot
+const SNAPSHOT_STORE: &str = "snapshots";
+/// the copies of the files of a snapshot are kept below `.snapshots/<name>/`
+pub const SNAPSHOT_DIR: &str = ".snapshots";
+
+/// a point-in-time copy of a directory
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Snapshot {
+    pub name: String,
+    /// the directory the snapshot was taken of, empty for everything
+    pub dir: String,
+    pub owner: String,
+    pub created: i64,
+    /// how many files were copied
+    pub files: usize,
+}
+
+#[derive(Deserialize, Serialize, Default)]
+pub struct SnapshotRequest {
+    pub name: String,
+    /// only used when creating a snapshot
+    #[serde(default)]
+    pub path: String,
+}
+
+lazy_static! {
+    static ref SNAPSHOTS: Mutex<BTreeMap<String, Snapshot>> = Mutex::new(BTreeMap::new());
+}
+
+/// snapshot names are a single path segment
+fn valid(name: &str) -> bool {
+    !name.is_empty() && name != "." && name != ".." && !name.contains('/')
+}
+
+/// true for the copies kept by snapshots, which can't be changed and aren't listed
+pub fn hidden(file: &str) -> bool {
+    file.trim_start_matches('/') == SNAPSHOT_DIR
+        || file
+            .trim_start_matches('/')
+            .starts_with(&format!("{}/", SNAPSHOT_DIR))
+}
+
+/// the path of the file relative to the directory, none when it isn't below it. The root holds
+/// every file but the copies kept by snapshots
+pub fn below<'a>(file: &'a str, dir: &str) -> Option<&'a str> {
+    if dir.is_empty() {
+        return Some(file).filter(|x| !self::hidden(x));
+    }
+    match file.strip_prefix(dir) {
+        Some("") => Some(""),
+        Some(rest) => rest.strip_prefix('/'),
+        None => None,
+    }
+}
+
+pub fn join(dir: &str, path: &str) -> String {
+    match (dir.is_empty(), path.is_empty()) {
+        (true, _) => path.to_string(),
+        (_, true) => dir.to_string(),
+        _ => format!("{}/{}", dir, path),
+    }
+}
+
+/// where the copies of the snapshot are kept
+fn root(name: &str) -> String {
+    self::join(SNAPSHOT_DIR, name)
+}
+
+pub fn load_snapshots() {
+    let loaded: Vec<Snapshot> = fs::read_to_string(SNAPSHOT_STORE)
+        .unwrap_or_default()
+        .lines()
+        .filter_map(|x| serde_json::from_str(x).ok())
+        .collect();
+    info!("loaded {} snapshot(s)", loaded.len());
+
+    if let Ok(mut snapshots) = SNAPSHOTS.lock() {
+        *snapshots = loaded
+            .into_iter()
+            .map(|x| (x.name.to_string(), x))
+            .collect();
+    }
+}
+
+fn save_snapshots(snapshots: &BTreeMap<String, Snapshot>) {
+    self::write_snapshots(snapshots);
+    raft::record(Op::Snapshots(snapshots.values().cloned().collect()));
+}
+
+fn write_snapshots(snapshots: &BTreeMap<String, Snapshot>) {
+    let lines: Vec<String> = snapshots
+        .values()
+        .filter_map(|x| serde_json::to_string(x).ok())
+        .collect();
+
+    if let Err(e) = fs::write(SNAPSHOT_STORE, lines.join("\n") + "\n") {
+        warn!("unable to save the snapshots: {}", e);
+    }
+}
+
+pub fn snapshots() -> Vec<Snapshot> {
+    SNAPSHOTS
+        .lock()
+        .map(|x| x.values().cloned().collect())
+        .unwrap_or_default()
+}
+
+/// takes over the snapshots replicated from the leader
+pub fn replace_snapshots(replaced: Vec<Snapshot>) {
+    if let Ok(mut snapshots) = SNAPSHOTS.lock() {
+        *snapshots = replaced
+            .into_iter()
+            .map(|x| (x.name.to_string(), x))
+            .collect();
+        self::write_snapshots(&snapshots);
+    }
+}
+
+/* -------------------------------------------------------------------------------------------------
+a snapshot copies every file below the directory to `.snapshots/<name>/<path>`, sharing the chunks
+of the originals (see `master::replace_tree`). Taking one is instant and takes no space on the
+workers, the chunks are only kept around for longer: a chunk is deleted once neither the files nor
+any snapshot reference it. The snapshot belongs to whoever took it, it only needs read access to
+the directory.
+------------------------------------------------------------------------------------------------- */
+pub async fn create_snapshot(
+    State(state): State<Config>,
+    Extension(identity): Extension<Identity>,
+    Json(payload): Json<SnapshotRequest>,
+) -> Response {
+    let dir = payload.path.trim_matches('/').to_string();
+    if !self::valid(&payload.name) || self::hidden(&dir) {
+        return StatusCode::BAD_REQUEST.into_response();
+    }
+    if !acl::permitted(&identity, &dir, Permission::Read) {
+        return StatusCode::FORBIDDEN.into_response();
+    }
+
+    let (snapshot, tree) = {
+        let Ok(mut snapshots) = SNAPSHOTS.lock() else {
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        };
+        if snapshots.contains_key(&payload.name) {
+            return StatusCode::CONFLICT.into_response();
+        }
+
+        let tree = master::replace_tree(Some(&dir), &self::join(&self::root(&payload.name), &dir));
+        if tree.copied.is_empty() {
+            return (
+                StatusCode::NOT_FOUND,
+                "there are no files to take a snapshot of",
+            )
+                .into_response();
+        }
+
+        let snapshot = Snapshot {
+            name: payload.name.to_string(),
+            dir: dir.to_string(),
+            owner: identity.name.to_string(),
+            created: chrono::Utc::now().timestamp(),
+            files: tree.copied.len(),
+        };
+        snapshots.insert(snapshot.name.to_string(), snapshot.clone());
+        self::save_snapshots(&snapshots);
+        (snapshot, tree)
+    };
+
+    acl::claim(&self::root(&snapshot.name), &identity.name);
+    master::settle(tree, &state).await;
+
+    info!(
+        "snapshot [{}] of [/{}] holds {} file(s)",
+        snapshot.name, snapshot.dir, snapshot.files
+    );
+    Json(snapshot).into_response()
+}
+
+/// lists the snapshots the caller may read
+pub async fn list_snapshots(Extension(identity): Extension<Identity>) -> Response {
+    let snapshots: Vec<Snapshot> = self::snapshots()
+        .into_iter()
+        .filter(|x| acl::permitted(&identity, &self::root(&x.name), Permission::Read))
+        .collect();
+
+    Json(snapshots).into_response()
+}
+
+/// puts the directory back the way it was when the snapshot was taken, files added since are
+/// removed. The snapshot itself is kept so it can be restored again
+pub async fn restore_snapshot(
+    State(state): State<Config>,
+    Extension(identity): Extension<Identity>,
+    Json(payload): Json<SnapshotRequest>,
+) -> Response {
+    let (snapshot, tree) = {
+        let Ok(snapshots) = SNAPSHOTS.lock() else {
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        };
+        let Some(snapshot) = snapshots.get(&payload.name).cloned() else {
+            return StatusCode::NOT_FOUND.into_response();
+        };
+        if !acl::permitted(&identity, &snapshot.dir, Permission::Write)
+            || !acl::permitted(&identity, &self::root(&snapshot.name), Permission::Read)
+        {
+            return StatusCode::FORBIDDEN.into_response();
+        }
+
+        let from = self::join(&self::root(&snapshot.name), &snapshot.dir);
+        let tree = master::replace_tree(Some(&from), &snapshot.dir);
+        (snapshot, tree)
+    };
+
+    for name in tree.removed.iter().filter(|x| !tree.copied.contains(x)) {
+        acl::forget(name);
+    }
+    info!(
+        "restored [/{}] from snapshot [{}], {} file(s) removed and {} restored",
+        snapshot.dir,
+        snapshot.name,
+        tree.removed.len(),
+        tree.copied.len()
+    );
+    master::settle(tree, &state).await;
+
+    Json(snapshot).into_response()
+}
+
+/// drops the copies of the snapshot, along with the chunks only the snapshot still referenced
+pub async fn delete_snapshot(
+    State(state): State<Config>,
+    Extension(identity): Extension<Identity>,
+    Json(payload): Json<SnapshotRequest>,
+) -> Response {
+    let (snapshot, tree) = {
+        let Ok(mut snapshots) = SNAPSHOTS.lock() else {
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        };
+        let Some(snapshot) = snapshots.get(&payload.name).cloned() else {
+            return StatusCode::NOT_FOUND.into_response();
+        };
+        if !acl::permitted(&identity, &self::root(&snapshot.name), Permission::Write) {
+            return StatusCode::FORBIDDEN.into_response();
+        }
+
+        let tree = master::replace_tree(None, &self::root(&snapshot.name));
+        snapshots.remove(&snapshot.name);
+        self::save_snapshots(&snapshots);
+        (snapshot, tree)
+    };
+
+    acl::forget(&self::root(&snapshot.name));
+    info!(
+        "snapshot [{}] deleted along with {} file(s)",
+        snapshot.name,
+        tree.removed.len()
+    );
+    master::settle(tree, &state).await;
+
+    Json(snapshot).into_response()
+}
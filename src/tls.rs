@@ -0,0 +1,204 @@
+use crate::config;
+use crate::config::Config;
+use axum::async_trait;
+use axum::extract::{ConnectInfo, FromRequestParts};
+use axum::http::request::Parts;
+use axum::http::StatusCode;
+use axum::middleware::AddExtension;
+use axum::{Extension, Router};
+use axum_server::accept::Accept;
+use axum_server::tls_rustls::{RustlsAcceptor, RustlsConfig};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::WebPkiClientVerifier;
+use rustls::{ClientConfig, RootCertStore, ServerConfig};
+use std::fs;
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_rustls::server::TlsStream;
+use tower_layer::Layer;
+use tracing::{error, info};
+
+lazy_static! {
+    /// the cluster CA, when set every node is expected to serve https
+    static ref CA: Option<String> = config::get().and_then(|x| x.tls_ca);
+    static ref AGENT: ureq::Agent = self::build_agent();
+}
+
+/// the subject of the client certificate presented (and verified) on a connection
+#[derive(Clone)]
+struct PeerIdentity(Option<String>);
+
+/// identifies the node making a request, the common name of its client certificate when the
+/// cluster uses mutual TLS and its IP address otherwise
+pub struct NodeId(pub String);
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for NodeId {
+    type Rejection = StatusCode;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        match parts.extensions.get::<PeerIdentity>() {
+            Some(PeerIdentity(Some(subject))) => Ok(NodeId(subject.to_string())),
+            // a TLS connection without a client certificate can't be a cluster node
+            Some(PeerIdentity(None)) => Err(StatusCode::FORBIDDEN),
+            None => parts
+                .extensions
+                .get::<ConnectInfo<SocketAddr>>()
+                .map(|x| NodeId(x.0.ip().to_string()))
+                .ok_or(StatusCode::INTERNAL_SERVER_ERROR),
+        }
+    }
+}
+
+/// the common name of the client certificate the request was made with, if any
+pub fn peer(extensions: &axum::http::Extensions) -> Option<String> {
+    match extensions.get::<PeerIdentity>() {
+        Some(PeerIdentity(subject)) => subject.clone(),
+        None => None,
+    }
+}
+
+/// wraps the rustls acceptor to record the peer certificate of every connection
+#[derive(Clone)]
+struct IdentityAcceptor(RustlsAcceptor);
+
+impl<I, S> Accept<I, S> for IdentityAcceptor
+where
+    I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    S: Send + 'static,
+{
+    type Stream = TlsStream<I>;
+    type Service = AddExtension<S, PeerIdentity>;
+    type Future = Pin<Box<dyn Future<Output = io::Result<(Self::Stream, Self::Service)>> + Send>>;
+
+    fn accept(&self, stream: I, service: S) -> Self::Future {
+        let acceptor = self.0.clone();
+
+        Box::pin(async move {
+            let (stream, service) = acceptor.accept(stream, service).await?;
+            let subject = stream
+                .get_ref()
+                .1
+                .peer_certificates()
+                .and_then(|x| x.first())
+                .and_then(|x| self::common_name(x));
+            Ok((stream, Extension(PeerIdentity(subject)).layer(service)))
+        })
+    }
+}
+
+/// serves the router on all interfaces, using https if the node has a certificate and key
+pub async fn serve(app: Router, port: &i16, config: &Config) -> Result<(), io::Error> {
+    let addr: SocketAddr = format!("0.0.0.0:{}", port)
+        .parse()
+        .map_err(io::Error::other)?;
+    let service = app.into_make_service_with_connect_info::<SocketAddr>();
+
+    match (&config.tls_cert, &config.tls_key) {
+        (Some(cert), Some(key)) => {
+            info!("serving https with certificate {}", cert);
+            match CA.as_ref() {
+                Some(ca) => {
+                    let tls = self::server_config(ca, cert, key)?;
+                    axum_server::bind(addr)
+                        .acceptor(IdentityAcceptor(RustlsAcceptor::new(
+                            RustlsConfig::from_config(Arc::new(tls)),
+                        )))
+                        .serve(service)
+                        .await
+                }
+                None => {
+                    let tls = RustlsConfig::from_pem_file(cert, key).await?;
+                    axum_server::bind_rustls(addr, tls).serve(service).await
+                }
+            }
+        }
+        _ => axum::serve(tokio::net::TcpListener::bind(addr).await?, service).await,
+    }
+}
+
+/// the http client every node (and the cli) should use to talk to the cluster
+pub fn agent() -> ureq::Agent {
+    AGENT.clone()
+}
+
+/// worker urls are built by the master so the scheme has to be the same cluster wide
+pub fn scheme() -> &'static str {
+    match *CA {
+        Some(_) => "https",
+        None => "http",
+    }
+}
+
+/* -------------------------------------------------------------------------------------------------
+with a cluster CA the nodes authenticate each other: the server side accepts client certificates
+issued by the CA (the cli has none, so they are optional on the connection but required by the
+routes that only nodes may call) and the nodes present their own certificate as a client.
+------------------------------------------------------------------------------------------------- */
+fn server_config(ca: &str, cert: &str, key: &str) -> Result<ServerConfig, io::Error> {
+    let verifier = WebPkiClientVerifier::builder(Arc::new(self::load_roots(ca)?))
+        .allow_unauthenticated()
+        .build()
+        .map_err(io::Error::other)?;
+
+    ServerConfig::builder()
+        .with_client_cert_verifier(verifier)
+        .with_single_cert(self::load_certs(cert)?, self::load_key(key)?)
+        .map_err(io::Error::other)
+}
+
+/// redirects are followed by `auth::send_master`, ureq won't resend the body of a POST
+fn build_agent() -> ureq::Agent {
+    let builder = ureq::AgentBuilder::new().redirects(0);
+    let Some(ca) = CA.as_ref() else {
+        return builder.build();
+    };
+
+    match self::client_config(ca) {
+        Ok(tls) => builder.tls_config(Arc::new(tls)).build(),
+        Err(e) => {
+            // falling back to the default roots means the cluster certificates won't verify
+            error!("unable to load the TLS configuration: {}", e);
+            builder.build()
+        }
+    }
+}
+
+fn client_config(ca: &str) -> Result<ClientConfig, io::Error> {
+    let builder = ClientConfig::builder().with_root_certificates(self::load_roots(ca)?);
+
+    match config::get().and_then(|x| x.tls_cert.zip(x.tls_key)) {
+        Some((cert, key)) => builder
+            .with_client_auth_cert(self::load_certs(&cert)?, self::load_key(&key)?)
+            .map_err(io::Error::other),
+        None => Ok(builder.with_no_client_auth()),
+    }
+}
+
+fn load_roots(path: &str) -> Result<RootCertStore, io::Error> {
+    let mut roots = RootCertStore::empty();
+    for cert in self::load_certs(path)? {
+        roots.add(cert).map_err(io::Error::other)?;
+    }
+    Ok(roots)
+}
+
+fn load_certs(path: &str) -> Result<Vec<CertificateDer<'static>>, io::Error> {
+    rustls_pemfile::certs(&mut io::BufReader::new(fs::File::open(path)?)).collect()
+}
+
+fn load_key(path: &str) -> Result<PrivateKeyDer<'static>, io::Error> {
+    rustls_pemfile::private_key(&mut io::BufReader::new(fs::File::open(path)?))?.ok_or(
+        io::Error::other(format!("no private key found in {}", path)),
+    )
+}
+
+fn common_name(cert: &CertificateDer) -> Option<String> {
+    let (_, cert) = x509_parser::parse_x509_certificate(cert).ok()?;
+    let name = cert.subject().iter_common_name().next()?.as_str().ok()?;
+    Some(name.to_string())
+}
@@ -1,5 +1,16 @@
+use crate::checksum;
+use crate::checksum::Integrity;
+use crate::compression;
+use crate::compression::Codec;
 use crate::config;
 use crate::config::Config;
+use crate::encryption;
+use crate::erasure;
+use crate::limit;
+use crate::master;
+use crate::master::{MetaStore, Policy, Status};
+use crate::tls;
+use crate::tls::NodeId;
 use axum::extract;
 use axum::extract::State;
 use axum::http::StatusCode;
@@ -9,33 +20,76 @@ use axum::routing::{get, post};
 use axum::Router;
 use base64::prelude::*;
 use serde::{Deserialize, Serialize};
+use serde_json::json;
 use std::fs;
 use std::fs::remove_file;
+use std::io;
 use std::io::Write;
-use std::path::Path;
-use std::time::Duration;
-use tracing::{error, info};
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+use std::time::{Duration, Instant};
+use tracing::{error, info, warn};
 
 use crate::auth;
+use crate::auth::{Capability, Operation};
+
+/// returned instead of the chunk when the bytes on disk no longer match their checksums
+pub const CORRUPT_CHUNK: StatusCode = StatusCode::UNPROCESSABLE_ENTITY;
+
+/// extension of the sidecar file holding the chunk metadata next to each chunk
+const META_EXTENSION: &str = "meta";
+
+/// how long the scrubber rests after a full pass over the data directory
+const SCRUB_INTERVAL_IN_SECONDS: u64 = 60 * 60;
+
+/// an unreachable master shouldn't hold up the heartbeats to the others
+const HEARTBEAT_TIMEOUT_IN_SECONDS: u64 = 2;
+
+/// a key used to encrypt chunks at rest, only known to this worker node
+struct NodeKey {
+    id: String,
+    key: Vec<u8>,
+}
+
+lazy_static! {
+    /// the current node key comes first, followed by the previous key while rotating
+    static ref NODEKEYS: RwLock<Vec<NodeKey>> = RwLock::new(vec![]);
+}
 
 pub async fn init(port: &i16) {
     println!("{}", crate::LOGO);
     info!("launching node in [worker] mode on port {}...", port);
 
     if let Some(config) = config::get() {
+        if !self::load_node_keys(&config) {
+            error!("unable to load the node keys, please make sure 'RDFS_NODE_KEY' and 'RDFS_NODE_KEY_PREVIOUS' point to valid keyfiles");
+            return;
+        }
+
         let app = Router::new()
             .route("/", get(hello))
             .route("/get-chunk", post(get_chunk))
             .route("/store-chunk", post(store_chunk))
             .route("/delete-chunk", post(delete_chunk))
             .route("/send-chunk", post(send_chunk))
-            .route_layer(middleware::from_fn(auth::authorise))
+            .route("/tag-chunk", post(tag_chunk))
+            .route("/list-chunks", post(list_chunks))
+            .route_layer(middleware::from_fn_with_state(
+                config.clone(),
+                limit::throttle,
+            ))
+            .route_layer(middleware::from_fn_with_state(
+                config.clone(),
+                auth::authorise,
+            ))
+            .layer(middleware::from_fn(limit::concurrency))
             .with_state(config.clone());
 
-        let listener = tokio::net::TcpListener::bind(format!("0.0.0.0:{}", port))
-            .await
-            .unwrap();
-        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        let (port, server) = (*port, config.clone());
+        tokio::spawn(async move { tls::serve(app, &port, &server).await.unwrap() });
+
+        let scrubber = config.clone();
+        tokio::task::spawn_blocking(move || background_scrubber(scrubber));
 
         let _ = tokio::task::spawn_blocking(move || background_heartbeat(config)).await;
     } else {
@@ -48,16 +102,79 @@ pub struct MetaChunk {
     pub id: String,
 }
 
+/// the master's capability has to come along with every delete
 #[derive(Deserialize, Serialize)]
-struct Chunk {
-    id: String,
-    chunk: String,
+pub struct DeleteChunk {
+    pub id: String,
+    pub capability: Option<Capability>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub enum ChunkStatus {
+    /// the chunk no longer matches its stored checksums
+    #[default]
+    Corrupt,
+    /// a file in the data directory that has no checksums, or a sidecar without a chunk
+    Unexpected,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct ChunkReport {
+    pub id: String,
+    #[serde(default)]
+    pub status: ChunkStatus,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct Chunk {
+    pub id: String,
+    /// the raw (uncompressed) chunk
+    pub chunk: String,
+    /// SHA-256 of the decoded chunk, checked before anything is written to disk
+    pub hash: String,
+    /// the codec the chunk is stored with on the worker's disk
+    #[serde(default)]
+    pub codec: Codec,
+    /// granted by the master, only needed when storing the chunk
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub capability: Option<Capability>,
+}
+
+/// stored in a sidecar file next to every chunk
+#[derive(Deserialize, Serialize, Default)]
+struct ChunkMeta {
+    #[serde(default)]
+    codec: Codec,
+    /// id of the node key the chunk is encrypted with, empty when stored in plaintext
+    #[serde(default)]
+    key_id: String,
+    /// the metadata of every file referencing the chunk, as last sent by the master
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    files: Vec<MetaStore>,
+}
+
+/// sent by the master when a file starts (or stops) referencing a chunk that is already stored
+#[derive(Deserialize, Serialize)]
+pub struct TagChunk {
+    pub id: String,
+    pub file_id: String,
+    /// the file's metadata for the chunk, none once the file is removed
+    pub file: Option<MetaStore>,
+}
+
+/// a chunk along with the files referencing it, as reported to a recovering master
+#[derive(Deserialize, Serialize)]
+pub struct StoredChunk {
+    pub id: String,
+    pub files: Vec<MetaStore>,
 }
 
 #[derive(Deserialize, Serialize)]
 struct SendChunk {
     id: String,
     target: String,
+    /// the capability to store the chunk on the target, passed on as is
+    capability: Option<Capability>,
 }
 
 async fn hello(State(state): State<Config>) -> String {
@@ -66,45 +183,98 @@ async fn hello(State(state): State<Config>) -> String {
 }
 
 #[axum::debug_handler]
-async fn get_chunk(extract::Json(payload): extract::Json<MetaChunk>) -> Response {
+async fn get_chunk(
+    State(state): State<Config>,
+    extract::Json(payload): extract::Json<MetaChunk>,
+) -> Response {
     info!("get-chunk with ID [{}]", &payload.id);
     // todo: we can use regex to make sure that the payload ID is legal e.g <INT>-<GUIDv43> format
-    if !Path::new(&payload.id).exists() {
-        return StatusCode::NOT_FOUND.into_response();
-    }
-
-    if let Ok(chunk) = fs::read(&payload.id) {
-        return Json(Chunk {
+    match self::read_chunk(&state, &payload.id).await {
+        Ok((chunk, meta)) => Json(Chunk {
             id: payload.id,
+            hash: checksum::hash(&chunk),
             chunk: BASE64_STANDARD.encode(chunk),
+            codec: meta.codec,
+            capability: None,
         })
-        .into_response();
+        .into_response(),
+        Err(status) => status.into_response(),
     }
-    StatusCode::INTERNAL_SERVER_ERROR.into_response()
 }
 
 #[axum::debug_handler]
-async fn store_chunk(extract::Json(payload): extract::Json<Chunk>) -> Response {
+async fn store_chunk(
+    State(state): State<Config>,
+    extract::Json(payload): extract::Json<Chunk>,
+) -> Response {
     info!("store-chunk with ID [{}]", &payload.id);
 
-    if let Ok(mut file) = fs::File::create(&payload.id) {
-        if let Ok(chunk) = BASE64_STANDARD.decode(&payload.chunk) {
-            if let Ok(_) = file.write(&chunk) {
-                return Json(MetaChunk {
-                    id: payload.id.to_string(),
-                })
-                .into_response();
-            }
-        }
+    if !auth::permits(
+        &state.token,
+        payload.capability.as_ref(),
+        &payload.id,
+        Operation::Store,
+    ) {
+        warn!("chunk [{}] has no capability to be stored", &payload.id);
+        return StatusCode::FORBIDDEN.into_response();
+    }
+
+    let Ok(chunk) = BASE64_STANDARD.decode(&payload.chunk) else {
+        return StatusCode::BAD_REQUEST.into_response();
+    };
+
+    // content addressed chunks are named after their hash, so the name has to match as well
+    if checksum::hash(&chunk) != payload.hash
+        || (checksum::is_hash(&payload.id) && payload.id != payload.hash)
+    {
+        warn!(
+            "chunk [{}] does not match its hash, refusing to store",
+            &payload.id
+        );
+        return StatusCode::BAD_REQUEST.into_response();
+    }
+
+    // a repaired chunk keeps the files it already knew of, the master only sends what it has
+    let path = self::chunk_path(&state, &payload.id);
+    let mut meta = ChunkMeta {
+        codec: payload.codec,
+        key_id: String::new(),
+        files: self::load_meta(&path).files,
+    };
+    for file in payload.capability.into_iter().flat_map(|x| x.files) {
+        self::merge_file(&mut meta.files, file);
+    }
+    if self::write_chunk(&path, &chunk, meta).is_ok() {
+        return Json(MetaChunk {
+            id: payload.id.to_string(),
+        })
+        .into_response();
     }
     StatusCode::INTERNAL_SERVER_ERROR.into_response()
 }
 
 #[axum::debug_handler]
-async fn delete_chunk(extract::Json(payload): extract::Json<MetaChunk>) -> Response {
-    info!("delete-chunk with ID [{}]", &payload.id);
+async fn delete_chunk(
+    State(state): State<Config>,
+    NodeId(node): NodeId,
+    extract::Json(payload): extract::Json<DeleteChunk>,
+) -> Response {
+    info!("delete-chunk with ID [{}] from -> {}", &payload.id, node);
+
+    if !auth::permits(
+        &state.token,
+        payload.capability.as_ref(),
+        &payload.id,
+        Operation::Delete,
+    ) {
+        warn!("chunk [{}] has no capability to be deleted", &payload.id);
+        return StatusCode::FORBIDDEN.into_response();
+    }
 
-    if let Ok(_) = remove_file(&payload.id) {
+    let path = self::chunk_path(&state, &payload.id);
+    if remove_file(&path).is_ok() {
+        let _ = remove_file(checksum::sidecar(&path));
+        let _ = remove_file(self::meta_path(&path));
         return Json(MetaChunk { id: payload.id }).into_response();
     }
 
@@ -114,33 +284,409 @@ async fn delete_chunk(extract::Json(payload): extract::Json<MetaChunk>) -> Respo
 #[axum::debug_handler]
 async fn send_chunk(
     State(state): State<Config>,
+    NodeId(node): NodeId,
     extract::Json(payload): extract::Json<SendChunk>,
 ) -> Response {
-    info!("send-chunk [{}] to -> {}", &payload.id, &payload.target);
+    info!(
+        "send-chunk [{}] to -> {} from -> {}",
+        &payload.id, &payload.target, node
+    );
+
+    let (chunk, meta) = match self::read_chunk(&state, &payload.id).await {
+        Ok(chunk) => chunk,
+        Err(status) => return status.into_response(),
+    };
+
+    let data = Chunk {
+        id: payload.id.clone(),
+        hash: checksum::hash(&chunk),
+        chunk: BASE64_STANDARD.encode(chunk),
+        codec: meta.codec,
+        capability: payload.capability,
+    };
+    // NOTE: we may need to move this i/o call into it's own thread via spawn_blocking
+    match auth::send(&state, &format!("{}/store-chunk", payload.target), data) {
+        Ok(_) => Json(MetaChunk { id: payload.id }).into_response(),
+        _ => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    }
+}
+
+#[axum::debug_handler]
+async fn tag_chunk(
+    State(state): State<Config>,
+    extract::Json(payload): extract::Json<TagChunk>,
+) -> Response {
+    info!(
+        "tag-chunk [{}] for file [{}]",
+        &payload.id, &payload.file_id
+    );
 
-    if !Path::new(&payload.id).exists() {
+    let path = self::chunk_path(&state, &payload.id);
+    if !path.exists() {
         return StatusCode::NOT_FOUND.into_response();
     }
 
-    if let Ok(chunk) = fs::read(&payload.id) {
-        let data = Chunk {
-            id: payload.id.clone(),
-            chunk: BASE64_STANDARD.encode(chunk),
+    let mut meta = self::load_meta(&path);
+    match payload.file {
+        Some(file) => self::merge_file(&mut meta.files, file),
+        None => meta.files.retain(|x| x.file_id != payload.file_id),
+    }
+    match self::save_meta(&path, &meta) {
+        Ok(_) => Json(MetaChunk { id: payload.id }).into_response(),
+        Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    }
+}
+
+/// reports every chunk in the data directory along with the files referencing it, chunks stored
+/// before their files were recorded come back without any
+async fn list_chunks(State(state): State<Config>) -> Response {
+    let Ok(entries) = fs::read_dir(&state.data_dir) else {
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    };
+
+    let chunks: Vec<StoredChunk> = entries
+        .map_while(Result::ok)
+        .map(|x| x.path())
+        .filter(|x| x.is_file())
+        .filter(|x| {
+            let extension = x.extension().and_then(|y| y.to_str());
+            extension != Some(checksum::EXTENSION) && extension != Some(META_EXTENSION)
+        })
+        .filter_map(|x| {
+            Some(StoredChunk {
+                id: x.file_name()?.to_str()?.to_string(),
+                files: self::load_meta(&x).files,
+            })
+        })
+        .collect();
+
+    info!("listing {} stored chunk(s)", chunks.len());
+    Json(chunks).into_response()
+}
+
+/// adds the file's metadata for a chunk, unless a newer version of it is already known
+fn merge_file(files: &mut Vec<MetaStore>, file: MetaStore) {
+    match files
+        .iter_mut()
+        .find(|x| x.file_id == file.file_id && x.chunk_id == file.chunk_id)
+    {
+        Some(known) if known.version > file.version => {}
+        Some(known) => *known = file,
+        None => files.push(file),
+    }
+}
+
+/// reads a chunk back from disk, verifies it against its checksums and decompresses it
+async fn read_chunk(config: &Config, id: &str) -> Result<(Vec<u8>, ChunkMeta), StatusCode> {
+    let path = self::chunk_path(config, id);
+    if !path.exists() {
+        return Err(StatusCode::NOT_FOUND);
+    }
+
+    let stored = fs::read(&path).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    if !self::verify_chunk(config, id, &path, &stored).await {
+        return Err(CORRUPT_CHUNK);
+    }
+
+    let meta = self::load_meta(&path);
+    let chunk = self::decode_chunk(&stored, &meta).map_err(|e| {
+        error!("unable to decode chunk [{}]: {}", id, e);
+        CORRUPT_CHUNK
+    })?;
+    Ok((chunk, meta))
+}
+
+/// chunks are compressed first and then encrypted with the current node key (if any), the
+/// checksums are computed over exactly what ends up on disk
+fn write_chunk(path: &Path, chunk: &[u8], mut meta: ChunkMeta) -> Result<(), io::Error> {
+    meta.key_id = String::new();
+    let mut stored = compression::compress(meta.codec, chunk)?;
+
+    if let Some(current) = NODEKEYS.read().ok().as_ref().and_then(|x| x.first()) {
+        stored = encryption::seal(&current.key, &stored)
+            .ok_or(io::Error::other("unable to encrypt chunk"))?;
+        meta.key_id = current.id.to_string();
+    }
+
+    fs::File::create(path)?.write_all(&stored)?;
+    checksum::store(path, &stored)?;
+    self::save_meta(path, &meta)
+}
+
+fn decode_chunk(stored: &[u8], meta: &ChunkMeta) -> Result<Vec<u8>, io::Error> {
+    if meta.key_id.is_empty() {
+        return compression::decompress(meta.codec, stored);
+    }
+
+    let keys = NODEKEYS
+        .read()
+        .map_err(|_| io::Error::other("node keys unavailable"))?;
+    let key = keys
+        .iter()
+        .find(|x| x.id == meta.key_id)
+        .ok_or(io::Error::other(format!(
+            "unknown node key {}",
+            meta.key_id
+        )))?;
+    let compressed =
+        encryption::open(&key.key, stored).ok_or(io::Error::other("unable to decrypt chunk"))?;
+    compression::decompress(meta.codec, &compressed)
+}
+
+/// loads the node keys used for encryption at rest, returns false if a configured key is invalid
+fn load_node_keys(config: &Config) -> bool {
+    let mut keys: Vec<NodeKey> = vec![];
+
+    for path in [&config.node_key, &config.previous_node_key]
+        .into_iter()
+        .flatten()
+    {
+        let Some(key) = encryption::load_key(path) else {
+            return false;
         };
-        // NOTE: we may need to move this i/o call into it's own thread via spawn_blocking
-        match ureq::post(&format!("{}/store-chunk", payload.target))
-            .set("x-rdfs-token", &state.token)
-            .send_json(data)
-        {
-            Ok(_) => {
-                return Json(MetaChunk { id: payload.id }).into_response();
+        keys.push(NodeKey {
+            id: encryption::key_id(&key),
+            key,
+        });
+    }
+
+    if let Some(current) = keys.first() {
+        info!(
+            "chunks are encrypted at rest with node key [{}]",
+            current.id
+        );
+    }
+
+    match NODEKEYS.write() {
+        Ok(mut memory) => {
+            *memory = keys;
+            true
+        }
+        Err(_) => false,
+    }
+}
+
+fn meta_path(chunk: &Path) -> PathBuf {
+    let mut path = chunk.as_os_str().to_owned();
+    path.push(format!(".{}", META_EXTENSION));
+    PathBuf::from(path)
+}
+
+/// chunks stored before the metadata sidecar existed are uncompressed
+fn load_meta(chunk: &Path) -> ChunkMeta {
+    fs::read_to_string(self::meta_path(chunk))
+        .ok()
+        .and_then(|x| serde_json::from_str(&x).ok())
+        .unwrap_or_default()
+}
+
+fn save_meta(chunk: &Path, meta: &ChunkMeta) -> Result<(), io::Error> {
+    fs::write(self::meta_path(chunk), serde_json::to_string(meta)?)
+}
+
+/// tries every healthy replica in turn until one returns a chunk that matches its recorded hash,
+/// erasure coded chunks are reconstructed from any of their healthy shards instead
+pub fn fetch_chunk(chunk: &MetaStore, config: &Config) -> Option<Vec<u8>> {
+    let placements = chunk.placements();
+    let healthy = placements
+        .iter()
+        .filter(|(_, host)| matches!(host.status, Status::Healthy));
+
+    let bytes = match chunk.policy {
+        Policy::Replicated => healthy
+            .filter_map(|(name, host)| self::pull_chunk(&host.ip, name, config))
+            .find(|x| chunk.chunk_hash.is_empty() || checksum::hash(x) == chunk.chunk_hash)?,
+        Policy::Erasure { data, parity } => {
+            let mut shards: Vec<Option<Vec<u8>>> = vec![None; placements.len()];
+            let mut found = 0;
+            for (i, (name, host)) in placements.iter().enumerate() {
+                if found == data {
+                    break;
+                }
+                if !matches!(host.status, Status::Healthy) {
+                    continue;
+                }
+                shards[i] = self::pull_chunk(&host.ip, name, config);
+                found += shards[i].is_some() as usize;
             }
-            _ => {
-                return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+            let shards = erasure::reconstruct(shards, data, parity)?;
+            erasure::decode(&shards, data, chunk.size as usize)
+        }
+    };
+
+    if !chunk.chunk_hash.is_empty() && checksum::hash(&bytes) != chunk.chunk_hash {
+        warn!("chunk {} does not match its hash", chunk.chunk_id);
+        return None;
+    }
+    Some(bytes)
+}
+
+/// fetches a chunk from a worker node, content addressed chunks are checked against their name
+pub fn pull_chunk(ip: &str, id: &str, config: &Config) -> Option<Vec<u8>> {
+    let remote: Chunk = auth::send(
+        config,
+        &format!("{}/get-chunk", master::worker_url(ip)),
+        MetaChunk { id: id.to_string() },
+    )
+    .ok()?
+    .into_json()
+    .ok()?;
+
+    let chunk = BASE64_STANDARD.decode(remote.chunk).ok()?;
+    if checksum::is_hash(id) && checksum::hash(&chunk) != id {
+        warn!("chunk [{}] from {} does not match its hash", id, ip);
+        return None;
+    }
+    Some(chunk)
+}
+
+/// stores a chunk on a worker node, the worker compresses it with the given codec
+pub fn push_chunk(
+    ip: &str,
+    id: &str,
+    chunk: &[u8],
+    codec: Codec,
+    capability: Option<&Capability>,
+    config: &Config,
+) -> bool {
+    let data = Chunk {
+        id: id.to_string(),
+        hash: checksum::hash(chunk),
+        chunk: BASE64_STANDARD.encode(chunk),
+        codec,
+        capability: capability.cloned(),
+    };
+
+    auth::send(
+        config,
+        &format!("{}/store-chunk", master::worker_url(ip)),
+        data,
+    )
+    .is_ok()
+}
+
+fn chunk_path(config: &Config, id: &str) -> PathBuf {
+    Path::new(&config.data_dir).join(id)
+}
+
+/// checks the chunk against its sidecar checksums, a corrupt chunk is reported to the master so
+/// that it can be re-replicated from a good copy. Chunks without a sidecar are still served.
+async fn verify_chunk(config: &Config, id: &str, path: &Path, chunk: &[u8]) -> bool {
+    match checksum::verify(path, chunk) {
+        Ok(_) => true,
+        Err(Integrity::Missing) => {
+            warn!("chunk [{}] has no checksums, serving unverified", id);
+            true
+        }
+        Err(Integrity::Corrupt(block)) => {
+            error!("chunk [{}] is corrupt at block {}", id, block);
+            let config = config.clone();
+            let id = id.to_string();
+            let _ = tokio::task::spawn_blocking(move || {
+                report_chunk(&config, &id, ChunkStatus::Corrupt)
+            })
+            .await;
+            false
+        }
+    }
+}
+
+fn report_chunk(config: &Config, id: &str, status: ChunkStatus) {
+    let data = ChunkReport {
+        id: id.to_string(),
+        status: status.clone(),
+    };
+
+    if auth::send_master(config, "report-chunk", data).is_err() {
+        warn!("unable to report {:?} chunk [{}] to the master", status, id);
+    }
+}
+
+fn background_scrubber(config: Config) {
+    /* ---------------------------------------------------------------------------------------------
+    silent bit rot in chunks that are rarely read would otherwise never be noticed, so we slowly
+    walk the data directory and re-verify every chunk against its checksums. To keep this a low
+    priority task the reads are throttled to the configured scrub rate (bytes per second).
+    --------------------------------------------------------------------------------------------- */
+    info!(
+        "initiating the background scrubber at {} bytes/s...",
+        config.scrub_rate
+    );
+    loop {
+        if let Ok(entries) = fs::read_dir(&config.data_dir) {
+            for path in entries.map_while(Result::ok).map(|x| x.path()) {
+                if !path.is_file() {
+                    continue;
+                }
+                self::scrub_file(&config, &path);
             }
         }
+        std::thread::sleep(Duration::from_secs(SCRUB_INTERVAL_IN_SECONDS));
+    }
+}
+
+fn scrub_file(config: &Config, path: &Path) {
+    let Some(name) = path.file_name().and_then(|x| x.to_str()) else {
+        return;
+    };
+
+    let extension = path.extension().and_then(|x| x.to_str());
+    if extension == Some(checksum::EXTENSION) || extension == Some(META_EXTENSION) {
+        // sidecars are verified along with their chunk, we only care about leftovers here
+        let chunk = path.with_extension("");
+        if !chunk.exists() {
+            warn!("scrubber found a sidecar without a chunk [{}]", name);
+            self::report_chunk(config, name, ChunkStatus::Unexpected);
+        }
+        return;
+    }
+
+    let started = Instant::now();
+    let Ok(chunk) = fs::read(path) else {
+        return;
+    };
+
+    match checksum::verify(path, &chunk) {
+        Ok(_) => self::rotate_chunk(path, &chunk),
+        Err(Integrity::Missing) => {
+            warn!("scrubber found a file without checksums [{}]", name);
+            self::report_chunk(config, name, ChunkStatus::Unexpected);
+        }
+        Err(Integrity::Corrupt(block)) => {
+            error!("scrubber found chunk [{}] corrupt at block {}", name, block);
+            self::report_chunk(config, name, ChunkStatus::Corrupt);
+        }
+    }
+
+    let budget = Duration::from_secs_f64(chunk.len() as f64 / config.scrub_rate as f64);
+    if let Some(rest) = budget.checked_sub(started.elapsed()) {
+        std::thread::sleep(rest);
+    }
+}
+
+/// re-encrypts a chunk that is not (yet) encrypted with the current node key, this is how keys
+/// are rotated: start the worker with the new key and the old key as the previous key and wait for
+/// the scrubber to complete a full pass
+fn rotate_chunk(path: &Path, stored: &[u8]) {
+    let current = match NODEKEYS.read() {
+        Ok(keys) => keys.first().map(|x| x.id.to_string()),
+        Err(_) => None,
+    };
+    let meta = self::load_meta(path);
+
+    let Some(current) = current else {
+        return;
+    };
+    if meta.key_id == current {
+        return;
+    }
+
+    let rotated =
+        self::decode_chunk(stored, &meta).and_then(|chunk| self::write_chunk(path, &chunk, meta));
+    match rotated {
+        Ok(_) => info!("chunk {:?} re-encrypted with node key [{}]", path, current),
+        Err(e) => warn!("unable to re-encrypt chunk {:?}: {}", path, e),
     }
-    StatusCode::INTERNAL_SERVER_ERROR.into_response()
 }
 
 fn background_heartbeat(config: Config) {
@@ -148,9 +694,15 @@ fn background_heartbeat(config: Config) {
     loop {
         // TODO: later on we could sent worker node meta information e.g disk space
         // to the master node.
-        let _ = ureq::post(&format!("{}/heartbeat", config.endpoint))
-            .set("x-rdfs-token", &config.token)
-            .call();
+        // every master keeps track of the workers itself, whichever becomes the leader knows them
+        for endpoint in &config.endpoints {
+            let _ = auth::send_within(
+                &config,
+                &format!("{}/heartbeat", endpoint),
+                json!({}),
+                Duration::from_secs(HEARTBEAT_TIMEOUT_IN_SECONDS),
+            );
+        }
         std::thread::sleep(Duration::from_millis(4000));
     }
 }
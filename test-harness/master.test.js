@@ -0,0 +1,538 @@
+import { assertEquals, assertRejects } from "jsr:@std/assert";
+
+/* -------------------------------------------------------------------------------------------------
+these tests spin up their own master node (from the debug build) inside a temporary directory with a
+hand written snapshot, so make sure to run `cargo build` before running them.
+------------------------------------------------------------------------------------------------- */
+const Token = "695bfaf2-f381-470b-945c-6cb11fa7a73c";
+const Port = 8899;
+const Master = `http://localhost:${Port}`;
+const Binary = new URL("../target/debug/rdfs", import.meta.url).pathname;
+
+// two different files that happen to have the exact same content
+const Hash = "08b6763aa88822e8b2b1b8be0ebdf36426e907db76ee5fc26aa5d75836722024";
+const chunk = (id, name) => ({
+  file_id: id,
+  file_name: name,
+  hash: Hash,
+  chunk_id: 1,
+  chunk_hash: Hash,
+  hosts: [{ ip: "127.0.0.1", status: "Healthy" }],
+});
+
+async function start(dir, env = {}) {
+  const master = new Deno.Command(Binary, {
+    args: ["mode", "master", `${Port}`],
+    cwd: dir,
+    env: { RDFS_ENDPOINT: Master, RDFS_TOKEN: Token, ...env },
+    stdout: "null",
+    stderr: "null",
+  }).spawn();
+
+  for (let i = 0; i < 50; i++) {
+    try {
+      await call("list", {});
+      return master;
+    } catch {
+      await new Promise((r) => setTimeout(r, 100));
+    }
+  }
+  throw new Error("master node did not start");
+}
+
+async function stop(master) {
+  master.kill();
+  await master.status;
+}
+
+async function call(route, body, token = Token) {
+  const x = await fetch(`${Master}/${route}`, {
+    method: "POST",
+    headers: {
+      "x-rdfs-token": token,
+      "Content-Type": "application/json",
+    },
+    body: JSON.stringify(body),
+  });
+  return { status: x.status, body: await x.text() };
+}
+
+Deno.test("remove-duplicate-content", async () => {
+  const dir = await Deno.makeTempDir();
+  await Deno.writeTextFile(
+    `${dir}/snapshot`,
+    [chunk("a", "a.txt"), chunk("b", "b.txt")]
+      .map((x) => JSON.stringify(x) + "\n").join(""),
+  );
+
+  let master = await start(dir);
+  assertEquals((await call("remove", { name: "a.txt" })).status, 200);
+  assertEquals((await call("get", { name: "b.txt" })).status, 200);
+  await stop(master);
+
+  // replaying the tombstone must only drop the removed file
+  master = await start(dir);
+  assertEquals((await call("get", { name: "a.txt" })).status, 500);
+  assertEquals((await call("get", { name: "b.txt" })).status, 200);
+  await stop(master);
+
+  await Deno.remove(dir, { recursive: true });
+});
+
+Deno.test("remove-unknown-file", async () => {
+  const dir = await Deno.makeTempDir();
+  await Deno.writeTextFile(
+    `${dir}/snapshot`,
+    JSON.stringify(chunk("b", "b.txt")) + "\n",
+  );
+
+  const master = await start(dir);
+  assertEquals((await call("remove", { name: "nope.txt" })).status, 404);
+  await stop(master);
+
+  // nothing should have been written to the prune log
+  await assertRejects(() => Deno.stat(`${dir}/prune`));
+
+  await Deno.remove(dir, { recursive: true });
+});
+
+Deno.test("metadata-database", async () => {
+  const dir = await Deno.makeTempDir();
+  await Deno.writeTextFile(
+    `${dir}/snapshot`,
+    [chunk("a", "a.txt"), chunk("b", "b.txt")]
+      .map((x) => JSON.stringify(x) + "\n").join(""),
+  );
+  const env = { RDFS_METADATA_DB: `${dir}/meta.db` };
+
+  // the snapshot is imported once and set aside
+  let master = await start(dir, env);
+  assertEquals((await call("remove", { name: "a.txt" })).status, 200);
+  await stop(master);
+  await Deno.stat(`${dir}/snapshot.imported`);
+  await assertRejects(() => Deno.stat(`${dir}/snapshot`));
+  await assertRejects(() => Deno.stat(`${dir}/prune`));
+
+  // the removal must have been kept by the database alone
+  master = await start(dir, env);
+  assertEquals((await call("get", { name: "a.txt" })).status, 500);
+  assertEquals((await call("get", { name: "b.txt" })).status, 200);
+  await stop(master);
+
+  await Deno.remove(dir, { recursive: true });
+});
+
+Deno.test("namespace-snapshot", async () => {
+  const dir = await Deno.makeTempDir();
+  await Deno.writeTextFile(
+    `${dir}/snapshot`,
+    [chunk("a", "reports/a.txt"), chunk("b", "reports/b.txt")]
+      .map((x) => JSON.stringify(x) + "\n").join(""),
+  );
+
+  const master = await start(dir);
+  const nightly = { name: "nightly", path: "reports/" };
+  assertEquals((await call("create-snapshot", nightly)).status, 200);
+  assertEquals((await call("create-snapshot", nightly)).status, 409);
+  assertEquals(
+    JSON.parse((await call("list", {})).body),
+    ["reports/a.txt", "reports/b.txt"],
+  );
+
+  // the copies outlive the originals and can't be removed on their own
+  const copy = { name: ".snapshots/nightly/reports/a.txt" };
+  assertEquals((await call("remove", { name: "reports/a.txt" })).status, 200);
+  assertEquals((await call("get", copy)).status, 200);
+  assertEquals((await call("remove", copy)).status, 400);
+
+  assertEquals((await call("restore-snapshot", { name: "nightly" })).status, 200);
+  assertEquals((await call("get", { name: "reports/a.txt" })).status, 200);
+
+  assertEquals((await call("delete-snapshot", { name: "nightly" })).status, 200);
+  assertEquals((await call("get", copy)).status, 500);
+  assertEquals(JSON.parse((await call("list-snapshots", {})).body), []);
+  await stop(master);
+
+  await Deno.remove(dir, { recursive: true });
+});
+
+Deno.test("scoped-access", async () => {
+  const dir = await Deno.makeTempDir();
+  await Deno.writeTextFile(
+    `${dir}/snapshot`,
+    JSON.stringify(chunk("b", "b.txt")) + "\n",
+  );
+
+  let master = await start(dir);
+  const created = await call("create-token", { name: "ci", scopes: ["read"] });
+  assertEquals(created.status, 200);
+  const { token } = JSON.parse(created.body);
+
+  // a read only token can't remove files or mint new tokens
+  assertEquals((await call("get", { name: "b.txt" }, token)).status, 200);
+  assertEquals((await call("remove", { name: "b.txt" }, token)).status, 403);
+  assertEquals(
+    (await call("create-token", { name: "x", scopes: ["admin"] }, token)).status,
+    403,
+  );
+  await stop(master);
+
+  // tokens survive a restart until they are revoked
+  master = await start(dir);
+  assertEquals((await call("get", { name: "b.txt" }, token)).status, 200);
+  assertEquals((await call("revoke-token", { name: "ci" })).status, 200);
+  assertEquals((await call("get", { name: "b.txt" }, token)).status, 401);
+  await stop(master);
+
+  await Deno.remove(dir, { recursive: true });
+});
+
+async function hex(algorithm, key, data) {
+  const bytes = new TextEncoder().encode(data);
+  const digest = key
+    ? await crypto.subtle.sign(
+      "HMAC",
+      await crypto.subtle.importKey(
+        "raw",
+        new TextEncoder().encode(key),
+        { name: "HMAC", hash: algorithm },
+        false,
+        ["sign"],
+      ),
+      bytes,
+    )
+    : await crypto.subtle.digest(algorithm, bytes);
+  return [...new Uint8Array(digest)]
+    .map((x) => x.toString(16).padStart(2, "0")).join("");
+}
+
+// mirrors `auth::sign_share`, the file name is signed in place of a request body
+async function share(file, expires) {
+  const secret = await hex("SHA-256", Token, "rdfs-signing");
+  const canonical = `GET\n/share\n${await hex("SHA-256", null, file)}\n${expires}`;
+  const query = new URLSearchParams({
+    file,
+    expires: `${expires}`,
+    key: (await hex("SHA-256", Token, "rdfs-key")).slice(0, 16),
+    signature: await hex("SHA-256", secret, canonical),
+  });
+  return (await fetch(`${Master}/share?${query}`)).status;
+}
+
+Deno.test("share-link", async () => {
+  const dir = await Deno.makeTempDir();
+  await Deno.writeTextFile(
+    `${dir}/snapshot`,
+    JSON.stringify(chunk("b", "b.txt")) + "\n",
+  );
+
+  const master = await start(dir);
+  const now = Math.floor(Date.now() / 1000);
+
+  // no worker is running, so a valid link gets as far as fetching the chunks
+  assertEquals(await share("b.txt", now + 60), 503);
+  assertEquals(await share("nope.txt", now + 60), 404);
+  assertEquals(await share("b.txt", now - 60), 403);
+  assertEquals(await share("b.txt", now + 30 * 24 * 60 * 60), 403);
+
+  const tampered = await fetch(
+    `${Master}/share?file=b.txt&expires=${now + 60}&key=0000000000000000&signature=00`,
+  );
+  await tampered.body?.cancel();
+  assertEquals(tampered.status, 403);
+  await stop(master);
+
+  await Deno.remove(dir, { recursive: true });
+});
+
+Deno.test("acl-inheritance", async () => {
+  const dir = await Deno.makeTempDir();
+  await Deno.writeTextFile(
+    `${dir}/snapshot`,
+    ["team/a.txt", "team/sub/b.txt", "other.txt"]
+      .map((x) => JSON.stringify(chunk(x, x)))
+      .join("\n") + "\n",
+  );
+
+  const master = await start(dir);
+  const tokens = {};
+  for (const [name, scopes] of [["alice", ["write"]], ["bob", ["read"]]]) {
+    const created = await call("create-token", { name, scopes });
+    tokens[name] = JSON.parse(created.body).token;
+  }
+  const status = async (route, body, token) =>
+    (await call(route, body, token)).status;
+  const read = (name) => status("get", { name }, tokens.bob);
+
+  // open paths can't be claimed by changing their ACL
+  const root = { path: "", principal: "user:alice", permission: "admin" };
+  assertEquals(await status("chmod", root, tokens.alice), 403);
+  assertEquals(await status("chown", { path: "team", owner: "alice" }), 200);
+
+  assertEquals((await call("list", {}, tokens.bob)).body, '["other.txt"]');
+  assertEquals(await read("team/sub/b.txt"), 403);
+
+  // a grant on a directory covers everything below it, and nothing next to it
+  const sub = { path: "team/sub", principal: "user:bob", permission: "read" };
+  assertEquals(await status("chmod", sub, tokens.alice), 200);
+  assertEquals(await read("team/sub/b.txt"), 200);
+  assertEquals(await read("team/a.txt"), 403);
+  sub.permission = "admin";
+  assertEquals(await status("chmod", sub, tokens.bob), 403);
+  await stop(master);
+
+  await Deno.remove(dir, { recursive: true });
+});
+
+Deno.test("audit-log", async () => {
+  const dir = await Deno.makeTempDir();
+  await Deno.writeTextFile(
+    `${dir}/snapshot`,
+    ["team/a.txt", "other.txt"]
+      .map((x) => JSON.stringify(chunk(x, x)))
+      .join("\n") + "\n",
+  );
+
+  const master = await start(dir);
+  const ci = { name: "ci", scopes: ["write"] };
+  const { token } = JSON.parse((await call("create-token", ci)).body);
+  const remove = async (name, as) => (await call("remove", { name }, as)).status;
+  assertEquals(await remove("team/a.txt", token), 200);
+  assertEquals(await remove("other.txt"), 200);
+
+  // failed calls are recorded too, reads are not
+  assertEquals(await remove("team/b.txt", token), 404);
+  await call("list", {}, token);
+
+  const events = JSON.parse((await call("audit", { user: "ci" })).body);
+  assertEquals(events.map((x) => [x.operation, x.target, x.status]), [
+    ["remove", "team/a.txt", 200],
+    ["remove", "team/b.txt", 404],
+  ]);
+  const team = JSON.parse((await call("audit", { path: "team" })).body);
+  assertEquals(team.length, 2);
+  assertEquals((await call("audit", {}, token)).status, 403);
+  await stop(master);
+
+  await Deno.remove(dir, { recursive: true });
+});
+
+Deno.test("quota-usage", async () => {
+  const dir = await Deno.makeTempDir();
+  await Deno.writeTextFile(
+    `${dir}/snapshot`,
+    ["team/a.txt", "team/sub/b.txt", "other.txt"]
+      .map((x) => JSON.stringify({ ...chunk(x, x), size: 1000 }))
+      .join("\n") + "\n",
+  );
+
+  const master = await start(dir);
+  const quota = (subject, soft, hard) =>
+    call("set-quota", { subject, soft, hard });
+  assertEquals((await quota("dir:/team/", null, 4096)).status, 200);
+  assertEquals((await quota("dir:team", 4096, 1024)).status, 400);
+  assertEquals((await quota("team", null, 4096)).status, 400);
+
+  // every chunk in the snapshot has a single host, so physical equals logical
+  const usage = JSON.parse((await call("quota", { subject: "dir:team" })).body);
+  assertEquals(usage, [{
+    subject: "dir:team",
+    logical: 2000,
+    physical: 2000,
+    soft: null,
+    hard: 4096,
+  }]);
+  await stop(master);
+
+  await Deno.remove(dir, { recursive: true });
+});
+
+Deno.test("rate-limit", async () => {
+  const dir = await Deno.makeTempDir();
+  await Deno.writeTextFile(
+    `${dir}/snapshot`,
+    JSON.stringify(chunk("b", "b.txt")) + "\n",
+  );
+
+  const master = await start(dir, { RDFS_REQUEST_RATE: "2" });
+  const ci = { name: "ci", scopes: ["read"] };
+  const { token } = JSON.parse((await call("create-token", ci)).body);
+
+  // a second's worth of requests goes through, the next one has to wait
+  const limited = [];
+  for (let i = 0; i < 3; i++) {
+    const x = await fetch(`${Master}/list`, {
+      method: "POST",
+      headers: { "x-rdfs-token": token },
+    });
+    await x.body?.cancel();
+    limited.push([x.status, x.headers.get("retry-after")]);
+  }
+  assertEquals(limited, [[200, null], [200, null], [429, "1"]]);
+
+  // the nodes themselves are never limited
+  for (let i = 0; i < 3; i++) {
+    assertEquals((await call("list", {})).status, 200);
+  }
+  await stop(master);
+
+  await Deno.remove(dir, { recursive: true });
+});
+
+Deno.test("master-failover", async () => {
+  const ports = [8901, 8902, 8903];
+  const endpoints = ports.map((x) => `http://127.0.0.1:${x}`);
+  const dirs = await Promise.all(ports.map(() => Deno.makeTempDir()));
+  const masters = ports.map((port, i) =>
+    new Deno.Command(Binary, {
+      args: ["mode", "master", `${port}`],
+      cwd: dirs[i],
+      env: {
+        RDFS_ENDPOINT: endpoints.join(","),
+        RDFS_ADVERTISE: endpoints[i],
+        RDFS_TOKEN: Token,
+      },
+      stdout: "null",
+      stderr: "null",
+    }).spawn()
+  );
+
+  // followers redirect writes to the leader, fetch follows the redirect with the body
+  const create = async (base, name) => {
+    for (let i = 0; i < 100; i++) {
+      try {
+        const x = await fetch(`${base}/create-token`, {
+          method: "POST",
+          headers: { "x-rdfs-token": Token, "Content-Type": "application/json" },
+          body: JSON.stringify({ name, scopes: ["read"] }),
+        });
+        await x.body?.cancel();
+        if (x.status == 200) return new URL(x.url).origin;
+      } catch {
+        // not started yet, or just killed
+      }
+      await new Promise((r) => setTimeout(r, 100));
+    }
+    throw new Error("no leader was elected");
+  };
+  const names = async (base) => {
+    const x = await fetch(`${base}/list-tokens`, {
+      method: "POST",
+      headers: { "x-rdfs-token": Token },
+    });
+    return (await x.json()).map((y) => y.name).sort();
+  };
+
+  const leader = await create(endpoints[0], "ci");
+  const i = endpoints.indexOf(leader);
+  masters[i].kill();
+  await masters[i].status;
+
+  // the remaining two masters are still a majority
+  const others = endpoints.filter((x) => x != leader);
+  assertEquals(others.includes(await create(others[0], "backup")), true);
+  await new Promise((r) => setTimeout(r, 500));
+  for (const base of others) {
+    assertEquals(await names(base), ["backup", "ci"]);
+  }
+
+  for (const [j, master] of masters.entries()) {
+    if (j != i) await stop(master);
+  }
+  for (const dir of dirs) {
+    await Deno.remove(dir, { recursive: true });
+  }
+});
+
+Deno.test("shadow-master", async () => {
+  const dir = await Deno.makeTempDir();
+  const copy = await Deno.makeTempDir();
+  await Deno.writeTextFile(
+    `${dir}/snapshot`,
+    JSON.stringify(chunk("a", "a.txt")) + "\n",
+  );
+
+  const master = await start(dir);
+  const port = Port + 1;
+  const shadow = new Deno.Command(Binary, {
+    args: ["mode", "shadow", `${port}`],
+    cwd: copy,
+    env: { RDFS_ENDPOINT: Master, RDFS_TOKEN: Token },
+    stdout: "null",
+    stderr: "null",
+  }).spawn();
+  const read = async (route, body) => {
+    for (let i = 0; i < 50; i++) {
+      try {
+        const x = await fetch(`http://localhost:${port}/${route}`, {
+          method: "POST",
+          headers: { "x-rdfs-token": Token, "Content-Type": "application/json" },
+          body: JSON.stringify(body),
+        });
+        return { status: x.status, body: await x.text() };
+      } catch {
+        await new Promise((r) => setTimeout(r, 100));
+      }
+    }
+    throw new Error("shadow master did not start");
+  };
+
+  // the shadow copies the metadata of the master and keeps serving it once the master is gone
+  assertEquals((await call("chown", { path: "a.txt", owner: "alice" })).status, 200);
+  await new Promise((r) => setTimeout(r, 2500));
+  await stop(master);
+  assertEquals(await read("list", {}), { status: 200, body: '["a.txt"]' });
+  assertEquals(JSON.parse((await read("get", { name: "a.txt" })).body).length, 1);
+  assertEquals((await read("remove", { name: "a.txt" })).status, 405);
+  assertEquals(
+    (await Deno.readTextFile(`${copy}/acls`)).includes('"owner":"alice"'),
+    true,
+  );
+
+  shadow.kill();
+  await shadow.status;
+  await Deno.remove(dir, { recursive: true });
+  await Deno.remove(copy, { recursive: true });
+});
+
+Deno.test("metadata-recovery", async () => {
+  const dir = await Deno.makeTempDir();
+
+  // a fake worker on the default worker port holding the only chunk of a.txt
+  const worker = Deno.serve(
+    { port: 8888, hostname: "127.0.0.1", onListen() {} },
+    () => Response.json([{ id: Hash, files: [chunk("a", "a.txt")] }]),
+  );
+  const master = new Deno.Command(Binary, {
+    args: ["mode", "recover", `${Port}`],
+    cwd: dir,
+    env: { RDFS_ENDPOINT: Master, RDFS_TOKEN: Token },
+    stdout: "null",
+    stderr: "null",
+  }).spawn();
+
+  // everything but the heartbeats waits for the recovery to finish
+  let heartbeat;
+  for (let i = 0; i < 50 && !heartbeat; i++) {
+    try {
+      heartbeat = await call("heartbeat", {});
+    } catch {
+      await new Promise((r) => setTimeout(r, 100));
+    }
+  }
+  assertEquals(heartbeat.status, 200);
+  assertEquals((await call("list", {})).status, 503);
+
+  await new Promise((r) => setTimeout(r, 11000));
+  assertEquals(await call("list", {}), { status: 200, body: '["a.txt"]' });
+  const [recovered] = JSON.parse((await call("get", { name: "a.txt" })).body);
+  assertEquals(recovered.hosts, [{ ip: "127.0.0.1", status: "Healthy" }]);
+  assertEquals(
+    (await Deno.readTextFile(`${dir}/snapshot`)).includes('"file_id":"a"'),
+    true,
+  );
+
+  await stop(master);
+  await worker.shutdown();
+  await Deno.remove(dir, { recursive: true });
+});
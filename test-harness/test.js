@@ -6,6 +6,42 @@ some automations in order to spin up the worker node before running this test
 ------------------------------------------------------------------------------------------------- */
 const Token = "695bfaf2-f381-470b-945c-6cb11fa7a73c";
 
+async function hex(algorithm, key, data) {
+  const bytes = new TextEncoder().encode(data);
+  const digest = key
+    ? await crypto.subtle.sign(
+      "HMAC",
+      await crypto.subtle.importKey(
+        "raw",
+        new TextEncoder().encode(key),
+        { name: "HMAC", hash: algorithm },
+        false,
+        ["sign"],
+      ),
+      bytes,
+    )
+    : await crypto.subtle.digest(algorithm, bytes);
+  return [...new Uint8Array(digest)]
+    .map((x) => x.toString(16).padStart(2, "0")).join("");
+}
+
+// what the master grants for every chunk it plans to store or delete
+async function capability(chunk, operation) {
+  const expires = Math.floor(Date.now() / 1000) + 60;
+  const canonical = [
+    "POST",
+    `/${operation}-chunk`,
+    await hex("SHA-256", null, chunk),
+    expires,
+  ].join("\n");
+  const signature = await hex(
+    "SHA-256",
+    await hex("SHA-256", null, Token),
+    canonical,
+  );
+  return { chunk, operation, expires, signature };
+}
+
 Deno.test("x-rdfs-token", async () => {
   let _ = await fetch("http://localhost:8888/", {
     headers: {
@@ -55,6 +91,9 @@ Deno.test("store-chunk", async () => {
     body: JSON.stringify({
       "id": "test.txt",
       "chunk": "dGhpcyBpcyBhIHRlc3QgZmlsZSE=",
+      "hash":
+        "08b6763aa88822e8b2b1b8be0ebdf36426e907db76ee5fc26aa5d75836722024",
+      "capability": await capability("test.txt", "store"),
     }),
   }).then((x) => x.text().then((data) => ({ status: x.status, body: data })))
     .then((data) => {
@@ -63,6 +102,46 @@ Deno.test("store-chunk", async () => {
     });
 });
 
+Deno.test("store-chunk-bad-hash", async () => {
+  let _ = await fetch("http://localhost:8888/store-chunk", {
+    method: "POST",
+    headers: {
+      "x-rdfs-token": Token,
+      "Content-Type": "application/json",
+    },
+    body: JSON.stringify({
+      "id": "test-bad-hash.txt",
+      "chunk": "dGhpcyBpcyBhIHRlc3QgZmlsZSE=",
+      "hash": "5c9d231c8b6d10f43fd0768ca80755d2",
+      "capability": await capability("test-bad-hash.txt", "store"),
+    }),
+  }).then((x) => x.text().then((data) => ({ status: x.status, body: data })))
+    .then((data) => {
+      assertEquals(data.status, 400);
+    });
+});
+
+Deno.test("store-chunk-without-capability", async () => {
+  let _ = await fetch("http://localhost:8888/store-chunk", {
+    method: "POST",
+    headers: {
+      "x-rdfs-token": Token,
+      "Content-Type": "application/json",
+    },
+    body: JSON.stringify({
+      "id": "test-no-capability.txt",
+      "chunk": "dGhpcyBpcyBhIHRlc3QgZmlsZSE=",
+      "hash":
+        "08b6763aa88822e8b2b1b8be0ebdf36426e907db76ee5fc26aa5d75836722024",
+      // a capability for another chunk doesn't count either
+      "capability": await capability("test.txt", "store"),
+    }),
+  }).then((x) => x.text().then((data) => ({ status: x.status, body: data })))
+    .then((data) => {
+      assertEquals(data.status, 403);
+    });
+});
+
 Deno.test("delete-chunk", async () => {
   let _ = await fetch("http://localhost:8888/delete-chunk", {
     method: "POST",
@@ -70,7 +149,10 @@ Deno.test("delete-chunk", async () => {
       "x-rdfs-token": Token,
       "Content-Type": "application/json",
     },
-    body: JSON.stringify({ "id": "test.txt" }),
+    body: JSON.stringify({
+      "id": "test.txt",
+      "capability": await capability("test.txt", "delete"),
+    }),
   }).then((x) => x.text().then((data) => ({ status: x.status, body: data })))
     .then((data) => {
       // console.log(data.body);